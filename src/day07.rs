@@ -1,13 +1,343 @@
 use advent_of_code_rust_runner::{DayImplementation, Result, Context};
+use num::{BigUint, Zero, One};
 
-pub struct Day07;
+pub struct Day07 {
+    pub counting: Counting,
+    pub render: Option<Render>
+}
+
+/// How timelines are counted. The number of timelines can grow exponentially
+/// with the height of the manifold, so tall manifolds may need big integers.
+#[derive(Clone, Copy)]
+pub enum Counting {
+    /// Native integers, failing if the count overflows.
+    Checked,
+    /// Arbitrary-precision integers.
+    BigInt
+}
+
+/// How to draw the manifold after simulating it.
+#[derive(Clone, Copy)]
+pub enum Render {
+    /// Draw `|` in every empty cell that a beam passes through.
+    Beams,
+    /// Draw the number of digits in the count of timelines passing through each
+    /// empty cell, or `+` if there are ten or more.
+    Timelines
+}
+
+impl std::str::FromStr for Counting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "checked" => Ok(Counting::Checked),
+            "bigint" => Ok(Counting::BigInt),
+            _ => anyhow::bail!("'{}' is not a way of counting; expected checked or bigint", s)
+        }
+    }
+}
+
+impl std::str::FromStr for Render {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "beams" => Ok(Render::Beams),
+            "timelines" => Ok(Render::Timelines),
+            _ => anyhow::bail!("'{}' is not a way of rendering; expected beams or timelines", s)
+        }
+    }
+}
 
 pub struct Day07Context {
-    state: Vec<usize>
+    timelines: BigUint
+}
+
+trait TimelineCount: Clone + Zero + One + Into<BigUint> + std::fmt::Display {
+    fn add_timelines(&mut self, other: &Self) -> Result<()>;
+}
+
+impl TimelineCount for usize {
+    fn add_timelines(&mut self, other: &Self) -> Result<()> {
+        *self = self.checked_add(*other).context("Timeline count overflowed; set DAY07_COUNTING=bigint")?;
+        Ok(())
+    }
+}
+
+impl TimelineCount for BigUint {
+    fn add_timelines(&mut self, other: &Self) -> Result<()> {
+        *self += other;
+        Ok(())
+    }
+}
+
+/// The components that can appear in a manifold.
+///
+/// Beams enter each row travelling downwards. Mirrors turn a beam along the row,
+/// where it travels until it meets a mirror that turns it back downwards, leaves
+/// the side of the manifold, or hits anything else, which absorbs it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Component {
+    Empty,
+    Start,
+    /// `^`: splits a beam into the columns either side.
+    Splitter,
+    /// `<`: splits a beam into its own column and the one to the left.
+    LeftSplitter,
+    /// `>`: splits a beam into its own column and the one to the right.
+    RightSplitter,
+    /// `/`: turns a descending beam left, or a beam travelling left downwards.
+    ForwardMirror,
+    /// `\`: turns a descending beam right, or a beam travelling right downwards.
+    BackMirror,
+    /// `#`: stops any beam that hits it. The timelines it stops still count, as
+    /// the particle took that path.
+    Absorber
+}
+
+impl Component {
+    fn parse(cell: u8) -> Option<Self> {
+        Some(match cell {
+            b'.' => Component::Empty,
+            b'S' => Component::Start,
+            b'^' => Component::Splitter,
+            b'<' => Component::LeftSplitter,
+            b'>' => Component::RightSplitter,
+            b'/' => Component::ForwardMirror,
+            b'\\' => Component::BackMirror,
+            b'#' => Component::Absorber,
+            _ => return None,
+        })
+    }
+
+    fn symbol(&self) -> char {
+        match self {
+            Component::Empty => '.',
+            Component::Start => 'S',
+            Component::Splitter => '^',
+            Component::LeftSplitter => '<',
+            Component::RightSplitter => '>',
+            Component::ForwardMirror => '/',
+            Component::BackMirror => '\\',
+            Component::Absorber => '#',
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Heading {
+    Left,
+    Right
+}
+
+struct Manifold {
+    cells: Vec<Vec<Component>>,
+    width: usize,
+    start_row: usize,
+    start_col: usize
+}
+
+impl Manifold {
+    fn parse(input: &str) -> Result<Self> {
+        let lines: Vec<&str> = input.lines().collect();
+        let width = lines.first().context("Empty input")?.len();
+
+        let mut cells = Vec::with_capacity(lines.len());
+        let mut start = None;
+        for (row_ix, line) in lines.iter().enumerate() {
+            if line.len() != width {
+                anyhow::bail!("Invalid input: row {} has width {}, expected {}", row_ix + 1, line.len(), width);
+            }
+            let row = line
+                .bytes()
+                .enumerate()
+                .map(|(col_ix, cell)| {
+                    Component::parse(cell).with_context(|| format!("Invalid input: unexpected character '{}' at row {}, column {}", cell as char, row_ix + 1, col_ix + 1))
+                })
+                .collect::<Result<Vec<Component>>>()?;
+            if let Some(col_ix) = row.iter().position(|&c| c == Component::Start) {
+                if start.is_some() || row.iter().filter(|&&c| c == Component::Start).count() > 1 {
+                    anyhow::bail!("Invalid input: multiple starting positions");
+                }
+                start = Some((row_ix, col_ix));
+            }
+            cells.push(row);
+        }
+        let (start_row, start_col) = start.context("No starting position found")?;
+
+        Ok(Manifold {
+            cells,
+            width,
+            start_row,
+            start_col
+        })
+    }
+
+    /// Runs every beam through the manifold. If `record_paths` is set, the
+    /// simulation also keeps the number of timelines passing through each cell.
+    fn simulate<T: TimelineCount>(&self, record_paths: bool) -> Result<Simulation<T>> {
+        // The state holds the number of timelines in which a beam is travelling
+        // down each column, as it enters the current row.
+        let mut state = vec![T::zero(); self.width];
+        state[self.start_col] = T::one();
+
+        let mut simulation = Simulation {
+            splits: 0,
+            state: Vec::new(),
+            exited_left: T::zero(),
+            exited_right: T::zero(),
+            absorbed: T::zero(),
+            paths: record_paths.then(|| vec![vec![T::zero(); self.width]; self.cells.len()])
+        };
+        simulation.record(self.start_row, self.start_col, &T::one())?;
+        for (row_ix, row) in self.cells.iter().enumerate().skip(self.start_row + 1) {
+            let mut next_state = vec![T::zero(); self.width];
+            for (col, timelines) in state.iter().enumerate().filter(|(_, timelines)| !timelines.is_zero()) {
+                simulation.record(row_ix, col, timelines)?;
+                let col = col as isize;
+                match row[col as usize] {
+                    Component::Empty | Component::Start => simulation.descend(&mut next_state, col, timelines)?,
+                    Component::Splitter => {
+                        simulation.splits += 1;
+                        simulation.descend(&mut next_state, col - 1, timelines)?;
+                        simulation.descend(&mut next_state, col + 1, timelines)?;
+                    },
+                    Component::LeftSplitter => {
+                        simulation.splits += 1;
+                        simulation.descend(&mut next_state, col - 1, timelines)?;
+                        simulation.descend(&mut next_state, col, timelines)?;
+                    },
+                    Component::RightSplitter => {
+                        simulation.splits += 1;
+                        simulation.descend(&mut next_state, col, timelines)?;
+                        simulation.descend(&mut next_state, col + 1, timelines)?;
+                    },
+                    Component::ForwardMirror => simulation.travel(&mut next_state, row, row_ix, col - 1, Heading::Left, timelines)?,
+                    Component::BackMirror => simulation.travel(&mut next_state, row, row_ix, col + 1, Heading::Right, timelines)?,
+                    Component::Absorber => simulation.absorbed.add_timelines(timelines)?,
+                }
+            }
+            state = next_state;
+        }
+        simulation.state = state;
+
+        log::debug!(
+            "Timelines exiting left: {}, right: {}, absorbed: {}",
+            simulation.exited_left,
+            simulation.exited_right,
+            simulation.absorbed);
+
+        Ok(simulation)
+    }
+
+    fn render<T: TimelineCount>(&self, paths: &[Vec<T>], render: Render) -> String {
+        self.cells
+            .iter()
+            .zip(paths.iter())
+            .map(|(row, row_paths)| row
+                .iter()
+                .zip(row_paths.iter())
+                .map(|(&component, timelines)| {
+                    if component != Component::Empty || timelines.is_zero() {
+                        return component.symbol();
+                    }
+                    match render {
+                        Render::Beams => '|',
+                        Render::Timelines => {
+                            let digits = timelines.to_string().len() as u32;
+                            char::from_digit(digits, 10).unwrap_or('+')
+                        }
+                    }
+                })
+                .collect::<String>())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+struct Simulation<T: TimelineCount> {
+    splits: usize,
+    state: Vec<T>,
+    exited_left: T,
+    exited_right: T,
+    absorbed: T,
+    paths: Option<Vec<Vec<T>>>
+}
+
+impl<T: TimelineCount> Simulation<T> {
+    fn record(&mut self, row: usize, col: usize, timelines: &T) -> Result<()> {
+        match &mut self.paths {
+            Some(paths) => paths[row][col].add_timelines(timelines),
+            None => Ok(())
+        }
+    }
+
+    /// Sends timelines down the given column into the next row, or out of the
+    /// side of the manifold if that column is off the grid.
+    fn descend(&mut self, next_state: &mut [T], col: isize, timelines: &T) -> Result<()> {
+        if col < 0 {
+            self.exited_left.add_timelines(timelines)
+        } else if col as usize >= next_state.len() {
+            self.exited_right.add_timelines(timelines)
+        } else {
+            next_state[col as usize].add_timelines(timelines)
+        }
+    }
+
+    /// Follows a beam travelling along a row from the given column until it
+    /// leaves the row.
+    fn travel(&mut self, next_state: &mut [T], row: &[Component], row_ix: usize, mut col: isize, heading: Heading, timelines: &T) -> Result<()> {
+        let step = match heading {
+            Heading::Left => -1,
+            Heading::Right => 1
+        };
+        while col >= 0 && (col as usize) < row.len() {
+            self.record(row_ix, col as usize, timelines)?;
+            match (row[col as usize], heading) {
+                (Component::Empty | Component::Start, _) => col += step,
+                (Component::ForwardMirror, Heading::Left) | (Component::BackMirror, Heading::Right) => {
+                    return next_state[col as usize].add_timelines(timelines);
+                },
+                (Component::ForwardMirror, Heading::Right) | (Component::BackMirror, Heading::Left) => {
+                    anyhow::bail!("Beam deflected upwards at row {}, column {}", row_ix + 1, col + 1);
+                },
+                _ => return self.absorbed.add_timelines(timelines),
+            }
+        }
+
+        // The beam ran off the end of the row.
+        if col < 0 {
+            self.exited_left.add_timelines(timelines)
+        } else {
+            self.exited_right.add_timelines(timelines)
+        }
+    }
+
+    /// Every timeline, whether it left the manifold or was absorbed inside it.
+    fn total_timelines(&self) -> Result<T> {
+        let mut total = self.exited_left.clone();
+        total.add_timelines(&self.exited_right)?;
+        total.add_timelines(&self.absorbed)?;
+        for timelines in self.state.iter() {
+            total.add_timelines(timelines)?;
+        }
+        Ok(total)
+    }
+}
+
+impl Day07 {
+    fn run<T: TimelineCount>(&self, manifold: &Manifold) -> Result<(usize, BigUint)> {
+        let simulation = manifold.simulate::<T>(self.render.is_some())?;
+        if let (Some(render), Some(paths)) = (self.render, &simulation.paths) {
+            println!("{}", manifold.render(paths, render));
+        }
+        Ok((simulation.splits, simulation.total_timelines()?.into()))
+    }
 }
 
 impl DayImplementation for Day07 {
-    type Output<'a> = usize;
+    type Output<'a> = BigUint;
     type Context<'a> = Day07Context;
 
     fn day(&self) -> u8 { 7 }
@@ -27,46 +357,19 @@ impl DayImplementation for Day07 {
 ...............
 .^.^.^.^.^...^.
 ...............") }
-    fn example_part_1_result(&self) -> Option<Self::Output<'static>> { Some(21) }
-    fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(40) }
+    fn example_part_1_result(&self) -> Option<Self::Output<'static>> { Some(BigUint::from(21u32)) }
+    fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(BigUint::from(40u32)) }
 
     fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
-        let mut lines = input.lines();
-        let first_line = lines.next().context("Empty input")?;
-        let initial_index = first_line.find('S').context("No starting position found")?;
-        let splitter_sets: Vec<Vec<usize>> = lines
-            .skip(1)
-            .step_by(2)
-            .map(|line| {
-                line
-                    .chars()
-                    .enumerate()
-                    .filter(|&(_i, c)| c == '^')
-                    .map(|(i, _c)| i)
-                    .collect::<Vec<usize>>()
-            })
-            .collect();
-
-        let mut state = vec![0usize; first_line.len()];
-        state[initial_index] = 1;
-
-        let mut splits = 0usize;
-        for splitters in splitter_sets {
-            for splitter_ix in splitters {
-                if state[splitter_ix] > 0 {
-                    splits += 1;
-                }
-
-                state[splitter_ix-1] += state[splitter_ix];
-                state[splitter_ix+1] += state[splitter_ix];
-                state[splitter_ix] = 0;
-            }
-        }
-
-        Ok((splits, Day07Context { state }))
+        let manifold = Manifold::parse(input)?;
+        let (splits, timelines) = match self.counting {
+            Counting::Checked => self.run::<usize>(&manifold)?,
+            Counting::BigInt => self.run::<BigUint>(&manifold)?
+        };
+        Ok((BigUint::from(splits), Day07Context { timelines }))
     }
 
     fn execute_part_2<'a>(&self, _input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
-        Ok(ctx.state.iter().sum())
+        Ok(ctx.timelines)
     }
 }
@@ -1,6 +1,48 @@
-use advent_of_code_rust_runner::{DayImplementation, Result};
+use advent_of_code_rust_runner::{DayImplementation, Result, Context};
+use itertools::Itertools;
+use std::path::PathBuf;
 
-pub struct Day08;
+pub struct Day08 {
+    /// How many connections to make for part 1. If not given, this is 10 for the
+    /// example and 1000 otherwise.
+    pub connections: Option<usize>,
+    /// Whether to print a report of how the circuits formed.
+    pub report: bool,
+    /// Where to write the final tree of connections, if anywhere.
+    pub export: Option<Export>
+}
+
+/// A file to export the tree of connections that joined all the circuits to.
+pub enum Export {
+    /// A Graphviz DOT file.
+    Dot(PathBuf),
+    /// A JSON file with `nodes` and `edges` arrays.
+    Json(PathBuf)
+}
+
+/// Chosen by the file's extension: `.dot` or `.gv` for DOT, `.json` for JSON.
+impl std::str::FromStr for Export {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = PathBuf::from(s);
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("dot" | "gv") => Ok(Export::Dot(path)),
+            Some("json") => Ok(Export::Json(path)),
+            _ => anyhow::bail!("Can't tell what to export to '{}'; expected a .dot, .gv or .json file", s)
+        }
+    }
+}
+
+impl Export {
+    fn write(&self, graph: &Graph) -> Result<()> {
+        let (path, contents) = match self {
+            Export::Dot(path) => (path, graph.to_dot()),
+            Export::Json(path) => (path, graph.to_json())
+        };
+        std::fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
 
 pub struct Day08Context {
     graph: Graph
@@ -8,118 +50,357 @@ pub struct Day08Context {
 
 struct Graph {
     boxes: Vec<JunctionBox>,
-    circuits: Vec<Option<Vec<usize>>>,
-    num_circuits: usize,
-    pairs: Vec<BoxPair>
+    tree: KdTree,
+    circuits: DisjointSet,
+    pairs: Vec<BoxPair>,
+    distance_threshold: u64,
+    max_distance_sq: u64,
+    connections_made: usize,
+    merges: Vec<Merge>
+}
+
+/// A connection that joined two separate circuits.
+struct Merge {
+    /// The number of connections made up to and including this one.
+    step: usize,
+    pair: BoxPair,
+    circuit_sizes: (usize, usize)
 }
 
 impl Graph {
-    fn parse(input: &str) -> Self {
-        let lines: Vec<&str> = input.lines().collect();
-        let num_lines = lines.len();
-
-        let mut boxes = Vec::with_capacity(num_lines);
-        let mut circuits = Vec::with_capacity(num_lines);
-        let mut pairs = Vec::with_capacity(num_lines * (num_lines - 1) / 2);
-
-        for (id, line) in lines.iter().enumerate() {
-            let mut coords = line.split(',');
-            let new_box = JunctionBox {
-                x: coords.next().unwrap().parse().unwrap(),
-                y: coords.next().unwrap().parse().unwrap(),
-                z: coords.next().unwrap().parse().unwrap(),
-                circuit: id
-            };
-            boxes.push(new_box);
-            circuits.push(Some(vec![id]));
-
-            for other_id in 0..id {
-                let pair = BoxPair {
-                    box_a: id,
-                    box_b: other_id,
-                    distance_sq: boxes[id].distance_to(&boxes[other_id])
-                };
-                pairs.push(pair);
-            }
+    fn parse(input: &str) -> Result<Self> {
+        let boxes: Vec<JunctionBox> = input
+            .lines()
+            .enumerate()
+            .map(|(line_ix, line)| JunctionBox::parse(line).with_context(|| format!("Invalid input on line {}", line_ix + 1)))
+            .collect::<Result<Vec<JunctionBox>>>()?;
+        let tree = KdTree::new(&boxes);
+
+        // There are far too many pairs of boxes to consider them all, but we're only
+        // going to process connections up to the point where all boxes are connected.
+        // So we only find and sort the pairs up to a distance threshold, and find more
+        // of them later if we run out. A good starting point is the distance from the
+        // box that is furthest from its nearest neighbour to that neighbour: no
+        // connected graph can do without an edge at least that long, and unless
+        // there's a cluster of outliers, that's the longest edge we'll need.
+        let distance_threshold = (0..boxes.len())
+            .filter_map(|id| tree.nearest_distance(&boxes, id))
+            .max()
+            .unwrap_or(0);
+
+        // Once the threshold reaches the size of the bounding box, every pair is in.
+        let min_corner = JunctionBox {
+            x: boxes.iter().map(|b| b.x).min().unwrap_or(0),
+            y: boxes.iter().map(|b| b.y).min().unwrap_or(0),
+            z: boxes.iter().map(|b| b.z).min().unwrap_or(0)
+        };
+        let max_corner = JunctionBox {
+            x: boxes.iter().map(|b| b.x).max().unwrap_or(0),
+            y: boxes.iter().map(|b| b.y).max().unwrap_or(0),
+            z: boxes.iter().map(|b| b.z).max().unwrap_or(0)
+        };
+        let max_distance_sq = min_corner.distance_to(&max_corner);
+
+        let mut pairs = tree.pairs_within(&boxes, None, distance_threshold);
+        pairs.sort_unstable_by_key(|pair| pair.distance_sq);
+
+        Ok(Graph {
+            circuits: DisjointSet::new(boxes.len()),
+            boxes,
+            tree,
+            pairs,
+            distance_threshold,
+            max_distance_sq,
+            connections_made: 0,
+            merges: Vec::new()
+        })
+    }
+
+    /// Gets the pair with the given index in order of distance, finding more pairs
+    /// if necessary. Returns `None` once every pair has been used.
+    fn pair(&mut self, index: usize) -> Option<BoxPair> {
+        while index >= self.pairs.len() && self.distance_threshold < self.max_distance_sq {
+            // All the pairs we already have are no further apart than the previous
+            // threshold, so appending the next band keeps the list in order.
+            let previous_threshold = self.distance_threshold;
+            self.distance_threshold = self.distance_threshold.max(1).saturating_mul(2);
+            log::debug!("Ran out of sorted pairs, raising distance threshold to {}", self.distance_threshold);
+            let mut new_pairs = self.tree.pairs_within(&self.boxes, Some(previous_threshold), self.distance_threshold);
+            new_pairs.sort_unstable_by_key(|pair| pair.distance_sq);
+            self.pairs.append(&mut new_pairs);
         }
+        self.pairs.get(index).copied()
+    }
 
-        // Okay, here's a slight cheat to speed things up.
-        //
-        // By far the most expensive part of the algorithm is sorting the pairs vector -
-        // with 1000 boxes, there are nearly 500,000 pairs to sort, which accounts for
-        // 90% of the runtime if done on the full list.
-        //
-        // However, we know that we're only going to process connections up to the point
-        // where all boxes are connected. Strictly speaking, we'd need to process the full
-        // list to know where that point is. What we do here is to make a slightly
-        // unreasonable assumption that there's a _solo_ outlier somewhere, and hence that
-        // if we find the box that is furthest away from ANY other box (i.e. furthest from
-        // its nearest neighbor), then that will be the longest distance of any edge that
-        // connects two boxes in the final connected graph.
-        //
-        // This breaks down in the case where two or more boxes are close to each other
-        // but far from all other boxes, but in practice it seems to work for my input, and
-        // for at least one other person's input that I have seen.
-        let distance_threshold = boxes
+    /// Connects the closest pair of boxes not yet connected directly. Returns
+    /// `false` if there are no pairs left.
+    fn connect_next(&mut self) -> bool {
+        let Some(pair) = self.pair(self.connections_made) else {
+            return false;
+        };
+        self.connections_made += 1;
+
+        let circuit_sizes = (self.circuits.size_of(pair.box_a), self.circuits.size_of(pair.box_b));
+        if self.circuits.union(pair.box_a, pair.box_b) {
+            self.merges.push(Merge {
+                step: self.connections_made,
+                pair,
+                circuit_sizes
+            });
+        }
+        true
+    }
+
+    fn print_size_distribution(&self) {
+        let mut sizes = self.circuits.set_sizes();
+        sizes.sort_unstable();
+        println!("Circuit sizes after {} connections:", self.connections_made);
+        for (size, group) in &sizes.iter().chunk_by(|&&size| size) {
+            println!("  {} box(es): {} circuit(s)", size, group.count());
+        }
+    }
+
+    fn print_merges(&self) {
+        println!("Connections that merged circuits:");
+        for merge in &self.merges {
+            let box_a = &self.boxes[merge.pair.box_a];
+            let box_b = &self.boxes[merge.pair.box_b];
+            println!(
+                "  Step {}: {},{},{} to {},{},{} (distance squared {}) joined circuits of {} and {}",
+                merge.step,
+                box_a.x, box_a.y, box_a.z,
+                box_b.x, box_b.y, box_b.z,
+                merge.pair.distance_sq,
+                merge.circuit_sizes.0,
+                merge.circuit_sizes.1);
+        }
+        if self.circuits.num_sets == 1 {
+            println!("All boxes connected at step {}", self.merges.last().map_or(0, |merge| merge.step));
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let nodes = self.boxes
             .iter()
             .enumerate()
-            .map(|(id, this_box)| boxes
-                .iter()
-                .enumerate()
-                .filter(|(other_id, _)| id != *other_id )
-                .map(|(_, other_box)| this_box.distance_to(other_box))
-                .min()
-                .unwrap())
-            .max()
-            .unwrap();
+            .map(|(id, b)| format!("  {} [label=\"{},{},{}\", x={}, y={}, z={}];", id, b.x, b.y, b.z, b.x, b.y, b.z));
+        let edges = self.merges
+            .iter()
+            .enumerate()
+            .map(|(order, merge)| format!(
+                "  {} -- {} [label=\"{}\", order={}, step={}, distance_sq={}];",
+                merge.pair.box_a,
+                merge.pair.box_b,
+                order + 1,
+                order + 1,
+                merge.step,
+                merge.pair.distance_sq));
+        std::iter::once("graph circuits {".to_string())
+            .chain(nodes)
+            .chain(edges)
+            .chain(std::iter::once("}\n".to_string()))
+            .join("\n")
+    }
 
-        let mut sorted_pairs: Vec<BoxPair> = pairs
-            .into_iter()
-            .filter(|pair| pair.distance_sq <= distance_threshold)
-            .collect();
-        sorted_pairs.sort_unstable_by_key(|pair| pair.distance_sq);
+    fn to_json(&self) -> String {
+        let nodes = self.boxes
+            .iter()
+            .enumerate()
+            .map(|(id, b)| format!("    {{\"id\": {}, \"x\": {}, \"y\": {}, \"z\": {}}}", id, b.x, b.y, b.z))
+            .join(",\n");
+        let edges = self.merges
+            .iter()
+            .enumerate()
+            .map(|(order, merge)| format!(
+                "    {{\"source\": {}, \"target\": {}, \"distance_sq\": {}, \"order\": {}, \"step\": {}}}",
+                merge.pair.box_a,
+                merge.pair.box_b,
+                merge.pair.distance_sq,
+                order + 1,
+                merge.step))
+            .join(",\n");
+        format!("{{\n  \"nodes\": [\n{}\n  ],\n  \"edges\": [\n{}\n  ]\n}}\n", nodes, edges)
+    }
+}
 
-        Graph {
-            boxes,
-            circuits,
-            num_circuits: num_lines,
-            pairs: sorted_pairs
+/// A static k-d tree over the junction boxes. It's stored implicitly: the median
+/// of each range of `order` is a node, splitting on the axis for its depth, and
+/// the ranges either side of it are its subtrees.
+struct KdTree {
+    order: Vec<usize>
+}
+
+impl KdTree {
+    fn new(boxes: &[JunctionBox]) -> Self {
+        let mut order: Vec<usize> = (0..boxes.len()).collect();
+        Self::build(boxes, &mut order, 0);
+        Self { order }
+    }
+
+    fn build(boxes: &[JunctionBox], order: &mut [usize], axis: usize) {
+        if order.len() <= 1 {
+            return;
         }
+        let mid = order.len() / 2;
+        order.select_nth_unstable_by_key(mid, |&id| boxes[id].coord(axis));
+        let (below, above) = order.split_at_mut(mid);
+        Self::build(boxes, below, (axis + 1) % 3);
+        Self::build(boxes, &mut above[1..], (axis + 1) % 3);
     }
 
-    fn connect(&mut self, pair: &BoxPair) {
-        let circuit_a_id = self.boxes[pair.box_a].circuit;
-        let circuit_b_id = self.boxes[pair.box_b].circuit;
+    /// Finds the squared distance from the given box to its nearest neighbour.
+    fn nearest_distance(&self, boxes: &[JunctionBox], id: usize) -> Option<u64> {
+        let mut best = None;
+        Self::search_nearest(boxes, &self.order, 0, id, &mut best);
+        best
+    }
 
-        if circuit_a_id == circuit_b_id {
-            // Already connected
+    fn search_nearest(boxes: &[JunctionBox], order: &[usize], axis: usize, id: usize, best: &mut Option<u64>) {
+        if order.is_empty() {
             return;
         }
+        let mid = order.len() / 2;
+        let node = order[mid];
+        if node != id {
+            let distance_sq = boxes[id].distance_to(&boxes[node]);
+            if best.is_none_or(|best| distance_sq < best) {
+                *best = Some(distance_sq);
+            }
+        }
 
-        let mut big_circuit: Vec<usize>;
-        let small_circuit: &Vec<usize>;
-        let big_circuit_id: usize;
-        let small_circuit_id: usize;
-        if self.circuits[circuit_a_id].as_ref().unwrap().len() >= self.circuits[circuit_b_id].as_ref().unwrap().len() {
-            big_circuit = self.circuits[circuit_a_id].take().unwrap();
-            big_circuit_id = circuit_a_id;
-            small_circuit = self.circuits[circuit_b_id].as_ref().unwrap();
-            small_circuit_id = circuit_b_id;
+        let target = boxes[id].coord(axis);
+        let split = boxes[node].coord(axis);
+        let (near, far) = if target < split {
+            (&order[..mid], &order[mid + 1..])
         } else {
-            big_circuit = self.circuits[circuit_b_id].take().unwrap();
-            big_circuit_id = circuit_b_id;
-            small_circuit = self.circuits[circuit_a_id].as_ref().unwrap();
-            small_circuit_id = circuit_a_id;
+            (&order[mid + 1..], &order[..mid])
+        };
+        Self::search_nearest(boxes, near, (axis + 1) % 3, id, best);
+        if best.is_none_or(|best| target.abs_diff(split).pow(2) <= best) {
+            Self::search_nearest(boxes, far, (axis + 1) % 3, id, best);
+        }
+    }
+
+    /// Finds every pair of boxes whose squared distance is more than `above` (if
+    /// given) and no more than `up_to`.
+    fn pairs_within(&self, boxes: &[JunctionBox], above: Option<u64>, up_to: u64) -> Vec<BoxPair> {
+        let mut pairs = Vec::new();
+        for id in 0..boxes.len() {
+            Self::search_within(boxes, &self.order, 0, id, above, up_to, &mut pairs);
         }
+        pairs
+    }
 
-        for box_id in small_circuit {
-            self.boxes[*box_id].circuit = big_circuit_id;
-            big_circuit.push(*box_id);
+    fn search_within(
+        boxes: &[JunctionBox],
+        order: &[usize],
+        axis: usize,
+        id: usize,
+        above: Option<u64>,
+        up_to: u64,
+        pairs: &mut Vec<BoxPair>
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let mid = order.len() / 2;
+        let node = order[mid];
+
+        // Each pair is found from both ends, so only record it from the later box.
+        if node < id {
+            let distance_sq = boxes[id].distance_to(&boxes[node]);
+            if distance_sq <= up_to && above.is_none_or(|above| distance_sq > above) {
+                pairs.push(BoxPair {
+                    box_a: id,
+                    box_b: node,
+                    distance_sq
+                });
+            }
         }
 
-        self.circuits[big_circuit_id] = Some(big_circuit);
-        self.circuits[small_circuit_id] = None;
-        self.num_circuits -= 1;
+        let target = boxes[id].coord(axis);
+        let split = boxes[node].coord(axis);
+        let reachable = target.abs_diff(split).pow(2) <= up_to;
+        if target <= split || reachable {
+            Self::search_within(boxes, &order[..mid], (axis + 1) % 3, id, above, up_to, pairs);
+        }
+        if target >= split || reachable {
+            Self::search_within(boxes, &order[mid + 1..], (axis + 1) % 3, id, above, up_to, pairs);
+        }
+    }
+}
+
+/// A disjoint-set forest tracking which circuit each junction box is part of.
+struct DisjointSet {
+    parents: Vec<usize>,
+    ranks: Vec<u8>,
+    sizes: Vec<usize>,
+    num_sets: usize
+}
+
+impl DisjointSet {
+    fn new(num_elements: usize) -> Self {
+        Self {
+            parents: (0..num_elements).collect(),
+            ranks: vec![0; num_elements],
+            sizes: vec![1; num_elements],
+            num_sets: num_elements
+        }
+    }
+
+    fn find(&mut self, element: usize) -> usize {
+        let mut root = element;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+
+        // Path compression: point everything we passed straight at the root.
+        let mut current = element;
+        while self.parents[current] != root {
+            let next = self.parents[current];
+            self.parents[current] = root;
+            current = next;
+        }
+
+        root
+    }
+
+    /// Merges the sets containing the two elements. Returns whether they were
+    /// previously separate.
+    fn union(&mut self, element_a: usize, element_b: usize) -> bool {
+        let root_a = self.find(element_a);
+        let root_b = self.find(element_b);
+        if root_a == root_b {
+            // Already connected
+            return false;
+        }
+
+        let (big_root, small_root) = if self.ranks[root_a] >= self.ranks[root_b] {
+            (root_a, root_b)
+        } else {
+            (root_b, root_a)
+        };
+        self.parents[small_root] = big_root;
+        self.sizes[big_root] += self.sizes[small_root];
+        if self.ranks[big_root] == self.ranks[small_root] {
+            self.ranks[big_root] += 1;
+        }
+        self.num_sets -= 1;
+        true
+    }
+
+    fn size_of(&mut self, element: usize) -> usize {
+        let root = self.find(element);
+        self.sizes[root]
+    }
+
+    fn set_sizes(&self) -> Vec<usize> {
+        self.parents
+            .iter()
+            .enumerate()
+            .filter(|&(element, &parent)| element == parent)
+            .map(|(root, _)| self.sizes[root])
+            .collect()
     }
 }
 
@@ -130,21 +411,47 @@ struct BoxPair {
     distance_sq: u64
 }
 
+/// The furthest a coordinate can be from zero. Squared distances then fit in a
+/// `u64`, and the product of two x coordinates in an `i64`.
+const MAX_COORDINATE: i64 = 1 << 30;
+
 struct JunctionBox {
-    x: u64,
-    y: u64,
-    z: u64,
-    circuit: usize
+    x: i64,
+    y: i64,
+    z: i64
 }
 
 impl JunctionBox {
+    fn parse(line: &str) -> Result<Self> {
+        let coords = line
+            .split(',')
+            .map(|coord| {
+                let value = coord.trim().parse::<i64>().with_context(|| format!("'{}' is not a valid coordinate", coord))?;
+                anyhow::ensure!((-MAX_COORDINATE..=MAX_COORDINATE).contains(&value), "Coordinate {} is outside the supported range of ±{}", value, MAX_COORDINATE);
+                Ok(value)
+            })
+            .collect::<Result<Vec<i64>>>()?;
+        let &[x, y, z] = coords.as_slice() else {
+            anyhow::bail!("Expected 3 coordinates, found {}", coords.len());
+        };
+        Ok(Self { x, y, z })
+    }
+
+    fn coord(&self, axis: usize) -> i64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z
+        }
+    }
+
     fn distance_to(&self, other: &JunctionBox) -> u64 {
         self.x.abs_diff(other.x).pow(2) + self.y.abs_diff(other.y).pow(2) + self.z.abs_diff(other.z).pow(2)
     }
 }
 
 impl DayImplementation for Day08 {
-    type Output<'a> = usize;
+    type Output<'a> = i64;
     type Context<'a> = Day08Context;
 
     fn day(&self) -> u8 { 8 }
@@ -172,29 +479,32 @@ impl DayImplementation for Day08 {
     fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(25272) }
 
     fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
-        let mut graph = Graph::parse(input);
-        let num_connections = if graph.boxes.len() < 30 { 10 } else { 1000 };
-        for i in 0..num_connections {
-            let pair = graph.pairs[i];
-            graph.connect(&pair);
-        }
-        let mut circuits: Vec<usize> = graph.circuits.clone().iter().flatten().map(|circuit| circuit.len()).collect();
-        circuits.select_nth_unstable_by(2, |a, b| b.cmp(a));
-        let answer = circuits.iter().take(3).product();
+        let mut graph = Graph::parse(input)?;
+        let num_connections = self.connections.unwrap_or(if graph.boxes.len() < 30 { 10 } else { 1000 });
+        while graph.connections_made < num_connections && graph.connect_next() {}
+        if self.report {
+            graph.print_size_distribution();
+        }
+        let mut circuits = graph.circuits.set_sizes();
+        circuits.sort_unstable_by(|a, b| b.cmp(a));
+        let answer = circuits.iter().take(3).product::<usize>() as i64;
         Ok((answer, Day08Context { graph }))
     }
 
     fn execute_part_2<'a>(&self, _input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
         let mut graph = ctx.graph;
-        let mut connection_ix = if graph.boxes.len() < 30 { 10 } else { 1000 };
-        let answer = loop {
-            let pair = graph.pairs[connection_ix];
-            graph.connect(&pair);
-            if graph.num_circuits == 1 {
-                break graph.boxes[pair.box_a].x as usize * graph.boxes[pair.box_b].x as usize
+        while graph.circuits.num_sets > 1 {
+            if !graph.connect_next() {
+                anyhow::bail!("Ran out of connections before all boxes were connected");
             }
-            connection_ix += 1;
-        };
-        Ok(answer)
+        }
+        if self.report {
+            graph.print_merges();
+        }
+        if let Some(export) = &self.export {
+            export.write(&graph)?;
+        }
+        let pair = graph.merges.last().context("No connections were needed")?.pair;
+        Ok(graph.boxes[pair.box_a].x * graph.boxes[pair.box_b].x)
     }
 }
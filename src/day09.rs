@@ -1,36 +1,66 @@
-use advent_of_code_rust_runner::{DayImplementation, Result};
-use std::collections::{BinaryHeap};
-use std::cmp::{Ordering, min, max};
+use advent_of_code_rust_runner::{DayImplementation, Result, Context};
+use std::cmp::{Ordering, Reverse, min, max};
+use std::collections::BinaryHeap;
+use itertools::Itertools;
+use std::path::{Path, PathBuf};
 
-pub struct Day09;
+pub struct Day09 {
+    /// Where to draw the loop and the largest rectangles, if anywhere.
+    pub svg: Option<PathBuf>,
+    /// How many of the largest rectangles to list for each part, if any.
+    pub top: Option<usize>
+}
 
 pub struct Day09Context {
     locations: Vec<Location>,
-    rectangles: BinaryHeap<Rectangle>
+    largest: Rectangle
 }
 
+/// The furthest a coordinate can be from zero, so that rectangle areas fit in a
+/// `u64`.
+const MAX_COORDINATE: i64 = 1 << 30;
+
 #[derive(Clone, Copy, Eq, PartialEq)]
 struct Location {
-    x: u64,
-    y: u64,
+    x: i64,
+    y: i64,
 }
 
 impl Location {
+    fn parse(line: &str) -> Result<Self> {
+        let coords = line
+            .split(',')
+            .map(|coord| {
+                let value = coord.trim().parse::<i64>().with_context(|| format!("'{}' is not a valid coordinate", coord))?;
+                anyhow::ensure!((-MAX_COORDINATE..=MAX_COORDINATE).contains(&value), "Coordinate {} is outside the supported range of ±{}", value, MAX_COORDINATE);
+                Ok(value)
+            })
+            .collect::<Result<Vec<i64>>>()?;
+        let &[x, y] = coords.as_slice() else {
+            anyhow::bail!("Expected 2 coordinates, found {}", coords.len());
+        };
+        Ok(Self { x, y })
+    }
+
     fn rect_with(&self, other: &Location) -> Rectangle {
         let corner_1 = Location { x: min(self.x, other.x), y: min(self.y, other.y) };
         let corner_2 = Location { x: max(self.x, other.x), y: max(self.y, other.y) };
         Rectangle {
             corner_1,
             corner_2,
-            area: (corner_2.x - corner_1.x + 1) * (corner_2.y - corner_1.y + 1)
+            red_tiles: (*self, *other),
+            area: (corner_2.x.abs_diff(corner_1.x) + 1) * (corner_2.y.abs_diff(corner_1.y) + 1)
         }
     }
 }
 
 #[derive(Eq, PartialEq)]
 struct Rectangle {
+    /// The corners with the smallest and largest coordinates.
     corner_1: Location,
     corner_2: Location,
+    /// The red tiles in opposite corners that the rectangle was made from.
+    red_tiles: (Location, Location),
     area: u64
 }
 
@@ -46,10 +76,111 @@ impl PartialOrd for Rectangle {
     }
 }
 
-struct BoundaryLine {
-    start: u64,
-    end: u64,
-    fixed: u64
+/// A coordinate-compressed map of which tiles are red or green.
+///
+/// Every distinct x coordinate of a red tile gets a column of its own, and each run
+/// of x coordinates between two of those becomes a single column too (likewise for
+/// y and rows). Every tile within one cell of the compressed grid is then on the
+/// same side of the loop, so we only need to work out the few cells, not the tiles.
+///
+/// The prefix sums still take a `u64` for each of the (2n+1)² cells, so this is
+/// only practical for up to a few thousand red tiles.
+struct TileMap {
+    xs: Vec<i64>,
+    ys: Vec<i64>,
+    /// Two-dimensional prefix sums of the number of cells, containing at least one
+    /// tile, that are outside the loop.
+    outside_counts: Vec<Vec<u64>>
+}
+
+impl TileMap {
+    fn new(locations: &[Location]) -> Self {
+        let xs: Vec<i64> = locations.iter().map(|loc| loc.x).sorted_unstable().dedup().collect();
+        let ys: Vec<i64> = locations.iter().map(|loc| loc.y).sorted_unstable().dedup().collect();
+
+        // Coordinate i is at index 2i+1; even indices are the gaps between them,
+        // with an extra gap around the outside.
+        let width = xs.len() * 2 + 1;
+        let height = ys.len() * 2 + 1;
+
+        // The sides of the loop as (fixed, start, end) in compressed coordinates.
+        let compress = |loc: &Location| (
+            xs.binary_search(&loc.x).unwrap() * 2 + 1,
+            ys.binary_search(&loc.y).unwrap() * 2 + 1
+        );
+        let mut horizontal_sides: Vec<(usize, usize, usize)> = Vec::new();
+        let mut vertical_sides: Vec<(usize, usize, usize)> = Vec::new();
+        for (loc1, loc2) in locations.iter().circular_tuple_windows() {
+            let (col1, row1) = compress(loc1);
+            let (col2, row2) = compress(loc2);
+            if row1 == row2 {
+                horizontal_sides.push((row1, min(col1, col2), max(col1, col2)));
+            } else {
+                vertical_sides.push((col1, min(row1, row2), max(row1, row2)));
+            }
+        }
+        vertical_sides.sort_unstable();
+
+        // Gaps between adjacent coordinates contain no tiles, so they can't make a
+        // rectangle invalid even if they're outside.
+        let gap_is_empty = |coords: &[i64], index: usize| {
+            index.is_multiple_of(2) && index > 0 && index < coords.len() * 2 && coords[index / 2] - coords[index / 2 - 1] == 1
+        };
+
+        // Work through the grid a row at a time, so that only the prefix sums are
+        // kept for the whole grid.
+        let mut outside_counts = vec![vec![0u64; width + 1]; height + 1];
+        let mut on_loop = vec![false; width];
+        for row in 0..height {
+            on_loop.fill(false);
+            for &(_, start, end) in horizontal_sides.iter().filter(|&&(fixed, _, _)| fixed == row) {
+                on_loop[start..=end].fill(true);
+            }
+            for &(col, _, _) in vertical_sides.iter().filter(|&&(_, start, end)| start <= row && row <= end) {
+                on_loop[col] = true;
+            }
+
+            // A cell that isn't on the loop is inside if a line from it to the left
+            // crosses the loop an odd number of times. The line is taken just below
+            // the cell, which is on the same side of the loop, so that it never runs
+            // along a side.
+            let crossings: Vec<usize> = vertical_sides
+                .iter()
+                .filter(|&&(_, start, end)| start <= row && row < end)
+                .map(|&(col, _, _)| col)
+                .collect();
+            let mut crossed = 0;
+            for col in 0..width {
+                while crossed < crossings.len() && crossings[crossed] < col {
+                    crossed += 1;
+                }
+                let outside = !on_loop[col] && crossed % 2 == 0 && !gap_is_empty(&ys, row) && !gap_is_empty(&xs, col);
+                outside_counts[row + 1][col + 1] = outside_counts[row][col + 1]
+                    + outside_counts[row + 1][col]
+                    - outside_counts[row][col]
+                    + outside as u64;
+            }
+        }
+
+        Self {
+            xs,
+            ys,
+            outside_counts
+        }
+    }
+
+    /// Checks whether every tile in the rectangle is red or green.
+    fn contains(&self, rectangle: &Rectangle) -> bool {
+        let col1 = self.xs.binary_search(&rectangle.corner_1.x).unwrap() * 2 + 1;
+        let col2 = self.xs.binary_search(&rectangle.corner_2.x).unwrap() * 2 + 2;
+        let row1 = self.ys.binary_search(&rectangle.corner_1.y).unwrap() * 2 + 1;
+        let row2 = self.ys.binary_search(&rectangle.corner_2.y).unwrap() * 2 + 2;
+        let outside = self.outside_counts[row2][col2]
+            + self.outside_counts[row1][col1]
+            - self.outside_counts[row1][col2]
+            - self.outside_counts[row2][col1];
+        outside == 0
+    }
 }
 
 impl DayImplementation for Day09 {
@@ -69,95 +200,242 @@ impl DayImplementation for Day09 {
     fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(24) }
 
     fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
-        let lines = input.lines().collect::<Vec<&str>>();
-        let mut locations: Vec<Location> = Vec::with_capacity(lines.len());
-        let mut rectangles: BinaryHeap<Rectangle> = BinaryHeap::new();
-        for i in 0..lines.len() {
-            let mut parts = lines[i].split(',');
-            let x: u64 = parts.next().unwrap().parse().unwrap();
-            let y: u64 = parts.next().unwrap().parse().unwrap();
-            let loc = Location { x, y };
-
-            for j in 0..i {
-                rectangles.push(loc.rect_with(&locations[j]));
-            }
-            locations.push(loc);
-        }
+        let locations = input
+            .lines()
+            .enumerate()
+            .map(|(i, line)| Location::parse(line).with_context(|| format!("Invalid input on line {}", i + 1)))
+            .collect::<Result<Vec<Location>>>()?;
+        validate_loop(&locations)?;
 
-        Ok((rectangles.peek().unwrap().area, Day09Context { locations, rectangles }))
+        let mut rectangles = largest_rectangles(&locations, self.top.unwrap_or(1), |_| true);
+        if self.top.is_some() {
+            print_rectangles("rectangles", &rectangles);
+        }
+        anyhow::ensure!(!rectangles.is_empty(), "Need at least two red tiles");
+        let largest = rectangles.swap_remove(0);
+        Ok((largest.area, Day09Context { locations, largest }))
     }
 
     fn execute_part_2<'a>(&self, _input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
-        let mut horizontal_boundaries: Vec<BoundaryLine> = Vec::with_capacity(ctx.locations.len());
-        let mut vertical_boundaries: Vec<BoundaryLine> = Vec::with_capacity(ctx.locations.len());
-        let mut rectangles = ctx.rectangles;
-        for ix in 0..ctx.locations.len()-1 {
-            add_boundary(&mut horizontal_boundaries, &mut vertical_boundaries, ctx.locations[ix], ctx.locations[ix+1]);            
+        let tile_map = TileMap::new(&ctx.locations);
+        let mut rectangles = largest_rectangles(&ctx.locations, self.top.unwrap_or(1), |rectangle| tile_map.contains(rectangle));
+        if self.top.is_some() {
+            print_rectangles("valid rectangles", &rectangles);
         }
-        add_boundary(&mut horizontal_boundaries, &mut vertical_boundaries, ctx.locations[0], ctx.locations[ctx.locations.len()-1]);            
-
-        let max_valid_area = 'outer: loop {
-            let rectangle = rectangles.pop().unwrap();
-
-            // First, we check whether any boundary line crosses into the interior of this rectangle.
-            for boundary in horizontal_boundaries.iter() {
-                if ((boundary.start <= rectangle.corner_1.x) != (boundary.end <= rectangle.corner_1.x) ||
-                    (boundary.start >= rectangle.corner_2.x) != (boundary.end >= rectangle.corner_2.x)) &&
-                   (boundary.fixed > rectangle.corner_1.y) &&
-                   (boundary.fixed < rectangle.corner_2.y) {
-                    // This boundary intersects one of the rectangle's vertical edges - the rectangle is invalid.
-                    continue 'outer;
-                }
-            }
-            for boundary in vertical_boundaries.iter() {
-                if ((boundary.start <= rectangle.corner_1.y) != (boundary.end <= rectangle.corner_1.y) ||
-                    (boundary.start >= rectangle.corner_2.y) != (boundary.end >= rectangle.corner_2.y)) &&
-                   (boundary.fixed > rectangle.corner_1.x) &&
-                   (boundary.fixed < rectangle.corner_2.x) {
-                    // This boundary intersects one of the rectangle's horizontal edges - the rectangle is invalid.
-                    continue 'outer;
-                }
-            }
+        anyhow::ensure!(!rectangles.is_empty(), "No valid rectangles");
+        let largest = rectangles.swap_remove(0);
+        if let Some(path) = &self.svg {
+            write_svg(path, &ctx.locations, &ctx.largest, &largest)?;
+        }
+        Ok(largest.area)
+    }
+}
+
+/// Draws the loop, filled in green, with its red tiles, the largest rectangle in
+/// blue and the largest valid rectangle in orange.
+fn write_svg(path: &Path, locations: &[Location], largest: &Rectangle, largest_valid: &Rectangle) -> Result<()> {
+    // Each tile is a unit square centred on its coordinates.
+    let min_x = locations.iter().map(|loc| loc.x).min().unwrap_or(0) - 1;
+    let max_x = locations.iter().map(|loc| loc.x).max().unwrap_or(0) + 1;
+    let min_y = locations.iter().map(|loc| loc.y).min().unwrap_or(0) - 1;
+    let max_y = locations.iter().map(|loc| loc.y).max().unwrap_or(0) + 1;
+    let points = locations.iter().map(|loc| format!("{},{}", loc.x, loc.y)).join(" ");
+    let red_tiles = locations
+        .iter()
+        .map(|loc| format!("  <rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"red\"/>", loc.x as f64 - 0.5, loc.y as f64 - 0.5))
+        .join("\n");
+    let outline = |rectangle: &Rectangle, colour: &str| format!(
+        "  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\" vector-effect=\"non-scaling-stroke\"/>",
+        rectangle.corner_1.x as f64 - 0.5,
+        rectangle.corner_1.y as f64 - 0.5,
+        rectangle.corner_2.x - rectangle.corner_1.x + 1,
+        rectangle.corner_2.y - rectangle.corner_1.y + 1,
+        colour);
+
+    let svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">
+  <polygon points=\"{}\" fill=\"green\" stroke=\"green\" stroke-width=\"1\" stroke-linejoin=\"miter\"/>
+{}
+{}
+{}
+</svg>
+",
+        min_x,
+        min_y,
+        max_x - min_x,
+        max_y - min_y,
+        points,
+        red_tiles,
+        outline(largest, "blue"),
+        outline(largest_valid, "orange"));
+    std::fs::write(path, svg).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// One side of the loop, from the red tile at `index` to the next one.
+struct Side {
+    index: usize,
+    fixed: i64,
+    start: i64,
+    end: i64
+}
+
+/// Checks that the red tiles form a simple loop of horizontal and vertical lines,
+/// and logs its shape.
+fn validate_loop(locations: &[Location]) -> Result<()> {
+    let num_tiles = locations.len();
+    if num_tiles < 4 {
+        anyhow::bail!("A loop needs at least 4 red tiles, found {}", num_tiles);
+    }
+
+    let mut horizontal_sides: Vec<Side> = Vec::with_capacity(num_tiles / 2);
+    let mut vertical_sides: Vec<Side> = Vec::with_capacity(num_tiles / 2);
+    for (index, (loc1, loc2)) in locations.iter().circular_tuple_windows().enumerate() {
+        let next_index = (index + 1) % num_tiles;
+        if loc1 == loc2 {
+            anyhow::bail!("Red tiles on lines {} and {} are both at {},{}", index + 1, next_index + 1, loc1.x, loc1.y);
+        } else if loc1.y == loc2.y {
+            horizontal_sides.push(Side { index, fixed: loc1.y, start: min(loc1.x, loc2.x), end: max(loc1.x, loc2.x) });
+        } else if loc1.x == loc2.x {
+            vertical_sides.push(Side { index, fixed: loc1.x, start: min(loc1.y, loc2.y), end: max(loc1.y, loc2.y) });
+        } else if next_index == 0 {
+            anyhow::bail!(
+                "The loop doesn't close: the last red tile ({},{}) isn't in line with the first ({},{})",
+                loc1.x, loc1.y, loc2.x, loc2.y);
+        } else {
+            anyhow::bail!(
+                "Red tiles on lines {} and {} ({},{} and {},{}) aren't in line",
+                index + 1, next_index + 1, loc1.x, loc1.y, loc2.x, loc2.y);
+        }
+    }
 
-            // Finally, we need to test for the edge case of this rectangle being wholly outside
-            // the shape, by virtue of coinciding entirely with concave boundary elements. To do
-            // this, we pick a point inside the rectangle, draw an imaginary ray (any direction
-            // will do), and count how many times it crosses a boundary. If it's an even number
-            // of times, we started outside the shape.
-            //
-            // We need to test a point truly inside the rectangle, not on its edge, or we'll
-            // "cross" boundaries that we were actually following. Technically, there are
-            // possible degenerate cases where a rectangle with height or width 2 follows a
-            // boundary along two sides but its opposite corner is outside the shape, and this
-            // wouldn't be caught by the boundary checks above - strictly speaking, we'd need
-            // to ray-check all four corners to test that. It so happens, though, that the
-            // input has no such cases, so we don't bother.
-            let interior_loc = Location { x: rectangle.corner_1.x + 1, y: rectangle.corner_1.y + 1 };
-            if interior_loc.x < rectangle.corner_2.x && interior_loc.y < rectangle.corner_2.y 
+    // Sides that follow on from each other meet at the red tile between them; any
+    // other sides meeting means the loop touches or crosses itself.
+    let adjacent = |side1: &Side, side2: &Side| {
+        (side1.index + 1) % num_tiles == side2.index || (side2.index + 1) % num_tiles == side1.index
+    };
+    let crossing_error = |side1: &Side, side2: &Side| anyhow::anyhow!(
+        "The loop crosses itself: the sides starting on lines {} and {} meet",
+        side1.index + 1,
+        side2.index + 1);
+
+    // Parallel sides can only meet if they're on the same line, so sort them into
+    // order along each line and compare each with the furthest-reaching before it.
+    for sides in [&mut horizontal_sides, &mut vertical_sides] {
+        sides.sort_unstable_by_key(|side| (side.fixed, side.start));
+        for (side_ix, side) in sides.iter().enumerate().skip(1) {
+            let furthest = sides[..side_ix]
+                .iter()
+                .rev()
+                .take_while(|other| other.fixed == side.fixed)
+                .max_by_key(|other| other.end);
+            if let Some(other) = furthest
+                && (side.start < other.end || (side.start == other.end && !adjacent(side, other)))
             {
-                let cross_count = vertical_boundaries
-                    .iter()
-                    .filter(|boundary| boundary.start <= interior_loc.y && boundary.end >= interior_loc.y && boundary.fixed < interior_loc.x)
-                    .count();
-                if cross_count % 2 == 0 {
-                    continue 'outer;
-                }
+                return Err(crossing_error(other, side));
+            }
+        }
+    }
+
+    // For perpendicular sides, look up the horizontal sides within each vertical
+    // side's span.
+    for vertical in vertical_sides.iter() {
+        let first = horizontal_sides.partition_point(|side| side.fixed < vertical.start);
+        for horizontal in horizontal_sides[first..].iter().take_while(|side| side.fixed <= vertical.end) {
+            if horizontal.start <= vertical.fixed && horizontal.end >= vertical.fixed && !adjacent(horizontal, vertical) {
+                return Err(crossing_error(horizontal, vertical));
             }
+        }
+    }
 
-            // Reaching here means this rectangle is valid.
-            break rectangle.area;
-        };
+    // The shoelace formula gives the area inside the line through the middle of the
+    // red and green tiles on the loop, and Pick's theorem then gives the number of
+    // whole tiles.
+    let twice_signed_area: i128 = locations
+        .iter()
+        .circular_tuple_windows()
+        .map(|(loc1, loc2)| loc1.x as i128 * loc2.y as i128 - loc2.x as i128 * loc1.y as i128)
+        .sum();
+    let perimeter: u128 = locations
+        .iter()
+        .circular_tuple_windows()
+        .map(|(loc1, loc2)| (loc1.x.abs_diff(loc2.x) + loc1.y.abs_diff(loc2.y)) as u128)
+        .sum();
+    let area = twice_signed_area.unsigned_abs() / 2;
+    log::info!(
+        "Loop of {} red tiles runs {} with area {} and perimeter {}, covering {} tiles",
+        num_tiles,
+        if twice_signed_area > 0 { "clockwise" } else { "anticlockwise" },
+        area,
+        perimeter,
+        area + perimeter / 2 + 1);
+
+    Ok(())
+}
+
+/// Finds the `count` largest rectangles with red tiles in opposite corners that
+/// pass the given validity check, largest first.
+///
+/// There are far too many rectangles to hold them all at once, so we consider each
+/// red tile in turn as one corner, starting with those that could be the corner of
+/// the biggest rectangles, and only check rectangles that would make the cut.
+fn largest_rectangles(locations: &[Location], count: usize, is_valid: impl Fn(&Rectangle) -> bool) -> Vec<Rectangle> {
+    if locations.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    let min_x = locations.iter().map(|loc| loc.x).min().unwrap();
+    let max_x = locations.iter().map(|loc| loc.x).max().unwrap();
+    let min_y = locations.iter().map(|loc| loc.y).min().unwrap();
+    let max_y = locations.iter().map(|loc| loc.y).max().unwrap();
+
+    // No rectangle with a corner at this location can be bigger than the one
+    // reaching to the far corner of the bounding box.
+    let area_bound = |loc: &Location| {
+        (max(loc.x.abs_diff(min_x), loc.x.abs_diff(max_x)) + 1) * (max(loc.y.abs_diff(min_y), loc.y.abs_diff(max_y)) + 1)
+    };
+    let corners: Vec<(u64, &Location)> = locations
+        .iter()
+        .map(|loc| (area_bound(loc), loc))
+        .sorted_unstable_by(|(bound_a, _), (bound_b, _)| bound_b.cmp(bound_a))
+        .collect();
+
+    // A min-heap of the best rectangles so far, so the one to beat is at the top.
+    let mut best: BinaryHeap<Reverse<Rectangle>> = BinaryHeap::with_capacity(count + 1);
+    let cutoff = |best: &BinaryHeap<Reverse<Rectangle>>| {
+        if best.len() < count { None } else { best.peek().map(|Reverse(rectangle)| rectangle.area) }
+    };
+    for (ix, &(bound, corner)) in corners.iter().enumerate() {
+        if cutoff(&best).is_some_and(|cutoff| bound <= cutoff) {
+            break;
+        }
 
-        Ok(max_valid_area)
+        // Any pairing with an earlier corner has already been considered.
+        for &(_, other_corner) in &corners[ix + 1..] {
+            let rectangle = corner.rect_with(other_corner);
+            // The red tiles on the other diagonal, if there are any, give the same
+            // rectangle again.
+            let seen = |best: &BinaryHeap<Reverse<Rectangle>>| best
+                .iter()
+                .any(|Reverse(other)| other.corner_1 == rectangle.corner_1 && other.corner_2 == rectangle.corner_2);
+            if cutoff(&best).is_none_or(|cutoff| rectangle.area > cutoff) && !seen(&best) && is_valid(&rectangle) {
+                best.push(Reverse(rectangle));
+                if best.len() > count {
+                    best.pop();
+                }
+            }
+        }
     }
+    best.into_sorted_vec().into_iter().map(|Reverse(rectangle)| rectangle).collect()
 }
 
-fn add_boundary(horizontal_boundaries: &mut Vec<BoundaryLine>, vertical_boundaries: &mut Vec<BoundaryLine>, loc1: Location, loc2: Location) {
-    if loc1.x == loc2.x {
-        // Differ in y
-        vertical_boundaries.push(BoundaryLine { start: min(loc1.y, loc2.y), end: max(loc1.y, loc2.y), fixed: loc1.x });
-    } else {
-        // Differ in x
-        horizontal_boundaries.push(BoundaryLine { start: min(loc1.x, loc2.x), end: max(loc1.x, loc2.x), fixed: loc1.y });
+fn print_rectangles(description: &str, rectangles: &[Rectangle]) {
+    println!("The {} largest {}:", rectangles.len(), description);
+    for rectangle in rectangles {
+        println!(
+            "  {}: {},{} to {},{}",
+            rectangle.area,
+            rectangle.red_tiles.0.x,
+            rectangle.red_tiles.0.y,
+            rectangle.red_tiles.1.x,
+            rectangle.red_tiles.1.y);
     }
-}
\ No newline at end of file
+}
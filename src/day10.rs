@@ -1,88 +1,215 @@
-use advent_of_code_rust_runner::{DayImplementation, Result};
+use advent_of_code_rust_runner::{DayImplementation, Result, Context};
 use std::collections::{VecDeque, HashSet};
+use std::time::{Duration, Instant};
+use num::Integer;
+use cp_sat::builder::{CpModelBuilder, IntVar, LinearExpr};
+use cp_sat::proto::{CpSolverStatus, SatParameters};
+use itertools::Itertools;
+use rayon::prelude::*;
+use crate::matrix::Matrix;
+
+pub struct Day10 {
+    pub lights_solver: LightsSolver,
+    pub solver: Solver,
+    /// Print the buttons pressed on each machine.
+    pub report: bool
+}
+
+/// How part 1 finds the fewest button presses for each machine.
+#[derive(Clone, Copy)]
+pub enum LightsSolver {
+    /// Gaussian elimination over GF(2), then a search of the null space.
+    Elimination,
+    /// Breadth-first search over the states of the lights.
+    BreadthFirst,
+    /// Run both, and fail if they ever disagree.
+    CrossCheck
+}
+
+/// How part 2 finds the fewest button presses for each machine.
+#[derive(Clone, Copy)]
+pub enum Solver {
+    /// Row reduce the joltage equations, then search the free buttons' presses.
+    Search,
+    /// Model each machine as an integer program and hand it to CP-SAT.
+    CpSat,
+    /// Run both, and fail if they ever disagree.
+    CrossCheck
+}
+
+impl std::str::FromStr for LightsSolver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "elimination" => Ok(LightsSolver::Elimination),
+            "bfs" => Ok(LightsSolver::BreadthFirst),
+            "cross-check" => Ok(LightsSolver::CrossCheck),
+            _ => anyhow::bail!("'{}' is not a lights solver; expected elimination, bfs or cross-check", s)
+        }
+    }
+}
+
+impl std::str::FromStr for Solver {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "search" => Ok(Solver::Search),
+            "cp-sat" => Ok(Solver::CpSat),
+            "cross-check" => Ok(Solver::CrossCheck),
+            _ => anyhow::bail!("'{}' is not a joltage solver; expected search, cp-sat or cross-check", s)
+        }
+    }
+}
 
-pub struct Day10;
+/// The most free buttons part 1 will search every combination of.
+const MAX_FREE_TOGGLES: usize = 25;
 
 pub struct Day10Context {
     machines: Vec<Machine>
 }
 
 struct State {
-    lights: u16,
-    pressed_buttons: u32
+    lights: BitSet,
+    pressed_buttons: BitSet
+}
+
+/// A set of lights or buttons, one bit each, of any width.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct BitSet {
+    words: Vec<u64>
+}
+
+impl BitSet {
+    fn new(num_lights: usize) -> Self {
+        Self { words: vec![0; num_lights.div_ceil(64)] }
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        (self.words[index / 64] >> (index % 64)) & 1 != 0
+    }
+
+    /// The lights in exactly one of the two sets.
+    fn toggle(&self, other: &BitSet) -> BitSet {
+        let mut toggled = self.clone();
+        toggled.toggle_in_place(other);
+        toggled
+    }
+
+    fn toggle_in_place(&mut self, other: &BitSet) {
+        self.words.iter_mut().zip(other.words.iter()).for_each(|(a, b)| *a ^= b);
+    }
+
+    fn count(&self) -> u32 {
+        self.words.iter().map(|word| word.count_ones()).sum()
+    }
+
+    fn intersects(&self, other: &BitSet) -> bool {
+        self.words.iter().zip(other.words.iter()).any(|(a, b)| a & b != 0)
+    }
+
+    fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words
+            .iter()
+            .enumerate()
+            .flat_map(|(word_ix, &word)| (0..64).filter(move |bit| (word >> bit) & 1 != 0).map(move |bit| word_ix * 64 + bit))
+    }
+}
+
+/// Written the way the input writes a button.
+impl std::fmt::Display for BitSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({})", self.indices().join(","))
+    }
 }
 
 struct Machine {
-    desired_state: u16,
-    buttons: Vec<u16>,
+    desired_state: BitSet,
+    buttons: Vec<BitSet>,
     joltages: Vec<u32>,
 }
 
 impl Machine {
-    fn parse(line: &str) -> Self {
+    fn parse(line: &str) -> Result<Self> {
         let parts: Vec<&str> = line.split_ascii_whitespace().collect();
-
-        let mut desired_state = 0;
-        let lights_bytes = parts[0].as_bytes();
-        for i in 1..lights_bytes.len()-1 {
-            if lights_bytes[i] == b'#' {
-                desired_state |= 1 << (i - 1);
+        let [lights_str, button_strs @ .., joltages_str] = parts.as_slice() else {
+            anyhow::bail!("Expected lights, buttons and joltages");
+        };
+
+        let lights_str = lights_str
+            .strip_prefix('[')
+            .and_then(|lights| lights.strip_suffix(']'))
+            .with_context(|| format!("'{}' is not a valid set of lights", lights_str))?;
+        let num_lights = lights_str.len();
+        let mut desired_state = BitSet::new(num_lights);
+        for (index, light) in lights_str.chars().enumerate() {
+            match light {
+                '#' => desired_state.insert(index),
+                '.' => {},
+                _ => anyhow::bail!("'{}' is not a valid light", light)
             }
         }
 
-        let buttons = parts[1..parts.len() - 1]
+        let buttons = button_strs
             .iter()
             .map(|btn_str| {
-                let mut button: u16 = 0;
-                let btn_bytes = btn_str.as_bytes();
-                btn_bytes.iter().skip(1).step_by(2).for_each(|&b| {
-                    let index = b.wrapping_sub(b'0') as usize;
-                    button |= 1 << index;
-                });
-                button
+                let indices = btn_str
+                    .strip_prefix('(')
+                    .and_then(|btn| btn.strip_suffix(')'))
+                    .with_context(|| format!("'{}' is not a valid button", btn_str))?;
+                let mut button = BitSet::new(num_lights);
+                for index in indices.split(',') {
+                    let index: usize = index.parse().with_context(|| format!("'{}' is not a valid light index", index))?;
+                    anyhow::ensure!(index < num_lights, "Button {} refers to light {}, but there are only {} lights", btn_str, index, num_lights);
+                    button.insert(index);
+                }
+                Ok(button)
             })
-            .collect();
+            .collect::<Result<Vec<BitSet>>>()?;
 
-        let joltages = parts[parts.len() - 1]
-            .trim_matches(|c| c == '{' || c == '}')
+        let joltages = joltages_str
+            .strip_prefix('{')
+            .and_then(|joltages| joltages.strip_suffix('}'))
+            .with_context(|| format!("'{}' is not a valid set of joltages", joltages_str))?
             .split(',')
-            .map(|j| j.parse::<u32>().unwrap())
-            .collect();
+            .map(|j| j.parse::<u32>().with_context(|| format!("'{}' is not a valid joltage", j)))
+            .collect::<Result<Vec<u32>>>()?;
+        anyhow::ensure!(joltages.len() == num_lights, "Expected {} joltages, found {}", num_lights, joltages.len());
 
-        Machine {
+        Ok(Machine {
             desired_state,
             buttons,
             joltages
-        }
+        })
     }
 }
 
-enum ConstraintRHS {
-    IsPositive,
-    DivisibleBy(i16)
+/// The solutions to a machine's joltage equations. Row reduction leaves each pivot
+/// button's presses expressed in terms of the free buttons' presses.
+struct JoltageSystem {
+    free_buttons: Vec<usize>,
+    pivots: Vec<PivotEquation>,
+    /// The range of presses possible for each free button.
+    bounds: Vec<(i64, i64)>
 }
 
-struct Constraint<'a> {
-    coefficients: &'a [i16],
-    rhs: ConstraintRHS
-}
-
-impl<'a> Constraint<'a> {
-    fn is_satisfied(&self, variable_values: &[i16]) -> bool {
-        let lhs: i16 = self.coefficients
-            .iter()
-            .zip(variable_values.iter())
-            .map(|(&coeff, &val)| coeff * val)
-            .sum::<i16>();
-        match self.rhs {
-            ConstraintRHS::IsPositive => lhs >= 0,
-            ConstraintRHS::DivisibleBy(divisor) => lhs % divisor == 0
-        }
-    }
+/// `divisor * presses = constant - sum(free_coefficients[k] * presses of free button k)`
+struct PivotEquation {
+    button: usize,
+    divisor: i64,
+    constant: i64,
+    free_coefficients: Vec<i64>,
+    /// The most presses this button could need, from the smallest joltage it affects.
+    max_presses: i64
 }
 
 impl DayImplementation for Day10 {
-    type Output<'a> = u32;
+    type Output<'a> = u64;
     type Context<'a> = Day10Context;
 
     fn day(&self) -> u8 { 10 }
@@ -93,413 +220,660 @@ impl DayImplementation for Day10 {
     fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(33) }
 
     fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
-        let machines: Vec<Machine> = input.lines().map(Machine::parse).collect();
+        let machines = input
+            .lines()
+            .enumerate()
+            .map(|(line_ix, line)| Machine::parse(line).with_context(|| format!("Invalid input on line {}", line_ix + 1)))
+            .collect::<Result<Vec<Machine>>>()?;
+
+        let solutions = solve_all(&machines, |machine| {
+            let presses = self.solve_lights(machine)?;
+            machine.verify_toggles(&presses)?;
+            Ok(presses)
+        })?;
+
+        let mut num_presses = 0;
+        for (machine_ix, (machine, presses)) in machines.iter().zip(solutions.iter()).enumerate() {
+            if self.report {
+                println!("Machine {}: {} ({} presses)", machine_ix + 1, presses.indices().map(|button| &machine.buttons[button]).join(" "), presses.count());
+            }
+            num_presses += presses.count() as u64;
+        }
 
-        let num_presses = machines
-            .iter()
-            .map(|machine| {
-                let mut states: VecDeque<State> = VecDeque::new();
-                states.push_back(State {
-                    lights: 0,
-                    pressed_buttons: 0
-                });
-                let mut seen_states: HashSet<u16> = HashSet::new();
-                seen_states.insert(0);
-                loop {
-                    let state = states.pop_front().unwrap();
-                    if state.lights == machine.desired_state {
-                        return state.pressed_buttons;
-                    }
+        Ok((num_presses, Day10Context { machines }))
+    }
 
-                    for button in &machine.buttons {
-                        let new_lights = state.lights ^ button;
+    fn execute_part_2<'a>(&self, _input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
+        let solutions = solve_all(&ctx.machines, |machine| {
+            let presses = self.solve_joltages(machine)?;
+            machine.verify_presses(&presses)?;
+            Ok(presses)
+        })?;
+
+        let mut total_presses = 0;
+        for (machine_ix, (machine, presses)) in ctx.machines.iter().zip(solutions.iter()).enumerate() {
+            let machine_total: i64 = presses.iter().sum();
+            if self.report {
+                let pressed = machine.buttons
+                    .iter()
+                    .zip(presses.iter())
+                    .filter(|&(_, &count)| count > 0)
+                    .map(|(button, count)| format!("{} x{}", button, count))
+                    .join(", ");
+                println!("Machine {}: {} ({} presses)", machine_ix + 1, pressed, machine_total);
+            }
+            total_presses += machine_total as u64;
+        }
+        Ok(total_presses)
+    }
+}
 
-                        // Check whether this button actually improves anything.
-                        let wrong_lights = state.lights ^ machine.desired_state;
-                        let state_better = (button & wrong_lights) != 0;
+/// Solves the machines in parallel, returning the solutions in input order. The
+/// time taken by each machine is logged, to help find any pathological ones.
+fn solve_all<T: Send>(machines: &[Machine], solve: impl Fn(&Machine) -> Result<T> + Sync) -> Result<Vec<T>> {
+    let results: Vec<(Result<T>, Duration)> = machines
+        .par_iter()
+        .map(|machine| {
+            let start = Instant::now();
+            let result = solve(machine);
+            (result, start.elapsed())
+        })
+        .collect();
 
-                        if state_better && seen_states.insert(new_lights) {
-                            states.push_back(State {
-                                lights: new_lights,
-                                pressed_buttons: state.pressed_buttons + 1
-                            });
-                        }
-                    }
+    for (machine_ix, (_, elapsed)) in results.iter().enumerate() {
+        log::debug!("Machine on line {} took {:?}", machine_ix + 1, elapsed);
+    }
+    if let Some((slowest_ix, (_, elapsed))) = results.iter().enumerate().max_by_key(|(_, (_, elapsed))| *elapsed) {
+        log::info!("Slowest machine was on line {}, taking {:?}", slowest_ix + 1, elapsed);
+    }
+
+    // Report the first failure in input order, whichever thread found it.
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(machine_ix, (result, _))| result.with_context(|| format!("Unable to solve the machine on line {}", machine_ix + 1)))
+        .collect()
+}
+
+impl Day10 {
+    /// Finds the buttons to press to turn on the desired lights, with the
+    /// configured solver.
+    fn solve_lights(&self, machine: &Machine) -> Result<BitSet> {
+        let presses = match self.lights_solver {
+            LightsSolver::Elimination => machine.fewest_toggles()?,
+            LightsSolver::BreadthFirst => machine.fewest_toggles_by_search(),
+            LightsSolver::CrossCheck => {
+                let elimination = machine.fewest_toggles()?;
+                let search = machine.fewest_toggles_by_search();
+                let (elimination_count, search_count) = (elimination.as_ref().map(BitSet::count), search.as_ref().map(BitSet::count));
+                if elimination_count != search_count {
+                    anyhow::bail!("Solvers disagree: elimination found {:?} presses, search found {:?}", elimination_count, search_count);
                 }
-            })
-            .sum();
+                elimination
+            }
+        };
+        presses.context("No combination of button presses reaches the desired lights")
+    }
 
-        Ok((num_presses, Day10Context { machines }))
+    /// Finds how many times to press each button to reach the joltages, with the
+    /// configured solver.
+    fn solve_joltages(&self, machine: &Machine) -> Result<Vec<i64>> {
+        let presses = match self.solver {
+            Solver::Search => machine.joltage_system()?.find_optimal_presses()?,
+            Solver::CpSat => machine.solve_with_cp_sat()?,
+            Solver::CrossCheck => {
+                let search = machine.joltage_system()?.find_optimal_presses()?;
+                let cp_sat = machine.solve_with_cp_sat()?;
+                let (search_total, cp_sat_total) = (search.as_ref().map(|p| p.iter().sum::<i64>()), cp_sat.as_ref().map(|p| p.iter().sum::<i64>()));
+                if search_total != cp_sat_total {
+                    anyhow::bail!("Solvers disagree: search found {:?} presses, CP-SAT found {:?}", search_total, cp_sat_total);
+                }
+                search
+            }
+        };
+        presses.context("No combination of button presses reaches the joltages")
     }
+}
 
-    fn execute_part_2<'a>(&self, _input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
-        let total_presses: u32 = ctx.machines
-            .iter()
-            .enumerate()
-            .map(|(_machine_index, machine)| {
-                // Turn the buttons and joltages into a system of linear equations,
-                // expressed as a matrix.
-                let num_variables = machine.buttons.len();
-                let num_equations = machine.joltages.len();
-                let mut matrix = machine
-                    .joltages
-                    .iter()
-                    .enumerate()
-                    .map(|(joltage_index, &joltage)| {
-                        let mut variables = vec![0; num_variables + 1];
-                        for (var_index, &button) in machine.buttons.iter().enumerate() {
-                            if button & (1 << joltage_index) != 0 {
-                                variables[var_index] = 1;
-                            }
-                        }
-                        variables[num_variables] = joltage as i16;
-                        variables
-                    })
-                    .collect::<Vec<Vec<i16>>>();
-
-                // Solve the system using Gaussian elimination.
-
-                // Step 1: forward elimination.
-                let mut pivot_row = 0usize;
-                for pivot_variable in 0..num_variables {
-                    // Find a row with a non-zero entry in the pivot column.
-                    let mut pivot_source = None;
-                    for row_index in pivot_row..num_equations {
-                        if matrix[row_index][pivot_variable] != 0 {
-                            pivot_source = Some(row_index);
-                            break;
-                        }
-                    }
-                    if let Some(pivot_source_index) = pivot_source {
-                        // Swap the pivot row into position.
-                        matrix.swap(pivot_source_index, pivot_row);
-
-                        // Eliminate entries below the pivot.
-                        let pivot_coefficient = matrix[pivot_row][pivot_variable];
-                        for row_index in (pivot_row + 1)..num_equations {
-                            let target_coefficient = matrix[row_index][pivot_variable];
-                            if target_coefficient != 0 {
-                                // Maintain integer arithmetic by using LCM to find multipliers.
-                                let lcm = num::integer::lcm(pivot_coefficient.abs() as u64, target_coefficient.abs() as u64) as i16;
-                                let pivot_multiplier = lcm / pivot_coefficient;
-                                let target_multiplier = lcm / target_coefficient;
-                                for col_index in pivot_variable..num_variables+1 {
-                                    matrix[row_index][col_index] =
-                                        (target_multiplier * matrix[row_index][col_index]) -
-                                        (pivot_multiplier * matrix[pivot_row][col_index]);
-                                }
-                            }
-                        }
-
-                        pivot_row += 1;
+impl Machine {
+    /// Replays the presses from part 1 to check that they turn on exactly the
+    /// desired lights.
+    fn verify_toggles(&self, presses: &BitSet) -> Result<()> {
+        let mut lights = BitSet::new(self.joltages.len());
+        for button in presses.indices() {
+            lights.toggle_in_place(&self.buttons[button]);
+        }
+        anyhow::ensure!(lights == self.desired_state, "Pressing {} doesn't turn on the desired lights", presses.indices().map(|button| &self.buttons[button]).join(" "));
+        Ok(())
+    }
+
+    /// Replays the presses from part 2 to check that they reach exactly the
+    /// desired joltages.
+    fn verify_presses(&self, presses: &[i64]) -> Result<()> {
+        anyhow::ensure!(presses.len() == self.buttons.len() && presses.iter().all(|&count| count >= 0), "Invalid button presses: {:?}", presses);
+        let mut joltages = vec![0; self.joltages.len()];
+        for (button, &count) in self.buttons.iter().zip(presses.iter()) {
+            for light in button.indices() {
+                joltages[light] += count;
+            }
+        }
+        let expected: Vec<i64> = self.joltages.iter().map(|&joltage| joltage as i64).collect();
+        anyhow::ensure!(joltages == expected, "Pressing buttons {:?} gives joltages {:?}, not {:?}", presses, joltages, expected);
+        Ok(())
+    }
+
+    /// Finds the fewest button presses that turn on the desired lights.
+    ///
+    /// Pressing a button twice undoes it, so this is a system of linear equations
+    /// over GF(2): one per light, with one unknown per button. Elimination finds
+    /// one solution and a basis for the null space, and every solution is that
+    /// one toggled by some combination of the basis. The combinations are visited
+    /// in Gray code order, so each only differs from the last by one vector.
+    /// That's 2^n combinations for n free buttons, so fails if there are more
+    /// than `MAX_FREE_TOGGLES`.
+    fn fewest_toggles(&self) -> Result<Option<BitSet>> {
+        let num_buttons = self.buttons.len();
+        let mut rows: Vec<BitSet> = (0..self.joltages.len())
+            .map(|light| {
+                let mut row = BitSet::new(num_buttons + 1);
+                for (button_index, button) in self.buttons.iter().enumerate() {
+                    if button.contains(light) {
+                        row.insert(button_index);
                     }
                 }
+                if self.desired_state.contains(light) {
+                    row.insert(num_buttons);
+                }
+                row
+            })
+            .collect();
 
-                // Step 2: back substitution.
-                let mut pivot_cols: Vec<Option<usize>> = vec![None; num_equations];
-                pivot_cols[0] = Some(0);
-                for row_index in (1..num_equations).rev() {
-                    let pivot_col = matrix[row_index]
-                        .iter()
-                        .position(|&val| val != 0);
-                    if let Some(col_index) = pivot_col {
-                        // Normalize the pivot row by dividing by the GCD of all its entries,
-                        // and ensuring the pivot column is positive.
-                        pivot_cols[row_index] = pivot_col;
-                        let mut gcd = matrix[row_index]
-                            .iter()
-                            .skip(col_index)
-                            .fold(0, |acc, &val| num::integer::gcd(acc, val));
-                        if matrix[row_index][col_index] < 0 {
-                            gcd *= -1;
-                        }
-
-                        if gcd != 1 {
-                            assert!(gcd != 0, "GCD should not be zero");
-                            for k in col_index..num_variables+1 {
-                                matrix[row_index][k] /= gcd;
-                            }
-                        }
-
-                        // Eliminate the pivot column from entries above the pivot.
-                        let pivot_coefficient = matrix[row_index][col_index];
-                        for sub_row_index in 0..row_index {
-                            let target_coefficient = matrix[sub_row_index][col_index];
-                            if target_coefficient != 0 {
-                                // Maintain integer arithmetic by using LCM to find multipliers.
-                                let lcm = num::integer::lcm(pivot_coefficient.abs() as u64, target_coefficient.abs() as u64) as i16;
-                                let pivot_multiplier = lcm / pivot_coefficient;
-                                let target_multiplier = lcm / target_coefficient;
-                                for k in 0..num_variables+1 {
-                                    matrix[sub_row_index][k] =
-                                        (target_multiplier * matrix[sub_row_index][k]) -
-                                        (pivot_multiplier * matrix[row_index][k]);
-                                }
-                            }
-                        }
-                    }
+        let mut pivot_cols: Vec<usize> = Vec::new();
+        for col in 0..num_buttons {
+            let pivot_row = pivot_cols.len();
+            let Some(source_row) = (pivot_row..rows.len()).find(|&row| rows[row].contains(col)) else {
+                continue;
+            };
+            rows.swap(source_row, pivot_row);
+            let pivot = rows[pivot_row].clone();
+            for (row_index, row) in rows.iter_mut().enumerate() {
+                if row_index != pivot_row && row.contains(col) {
+                    row.toggle_in_place(&pivot);
                 }
+            }
+            pivot_cols.push(col);
+        }
 
-                // Step 3: scale the rows to the LCM of the pivot coefficients, pull
-                // out the RHS values and free variable coefficients, and flip the
-                // free variable coefficient signs to reflect moving them to the RHS
-                // of the equations.
-                let lcm = pivot_cols
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(row_index, &opt_col_index)| {
-                        opt_col_index.map(|col_index| matrix[row_index][col_index])
-                    })
-                    .fold(1, |acc, val| num::integer::lcm(acc, val));
-
-                let mut variable_is_free: Vec<bool> = vec![false; num_variables];
-
-                // We initialize the free variable coefficients to the LCM, reflecting
-                // the fact that the matrix is going to effectively end up giving us
-                // the sum of the fixed variables multiplied by the LCM, so we need
-                // to add the missing free variables.
-                let mut total_coefficients = vec![lcm; num_variables+1];
-                total_coefficients[num_variables] = 0; // Base presses
-                for (row_index, pivot_col) in pivot_cols.iter().enumerate().filter_map(|(idx, &opt)| opt.map(|col| (idx, col))) {
-                    let scale = lcm / matrix[row_index][pivot_col];
-                    if scale != 1 {
-                        for col_index in pivot_col..num_variables+1 {
-                            matrix[row_index][col_index] *= scale;
-                        }
-                    }
-                    total_coefficients[num_variables] += matrix[row_index][num_variables];
-                    for other_col_index in pivot_col + 1..num_variables {
-                        let coefficient = matrix[row_index][other_col_index];
-                        if coefficient != 0 {
-                            // This variable affects this equation, so it can't be fixed.
-                            variable_is_free[other_col_index] = true;
-                            // Flip the sign of the coefficient, as we're moving it
-                            // from the LHS of the equation to the RHS.
-                            matrix[row_index][other_col_index] = -coefficient;
-                            // Pull it out.
-                            total_coefficients[other_col_index] -= coefficient;
-                        }
+        // Rows without a pivot say that 0 equals their last entry.
+        if rows[pivot_cols.len()..].iter().any(|row| row.contains(num_buttons)) {
+            return Ok(None);
+        }
+
+        // Pressing no free buttons leaves each pivot button pressed if its row
+        // needs the light toggled.
+        let mut presses = BitSet::new(num_buttons);
+        for (row, &col) in rows.iter().zip(pivot_cols.iter()) {
+            if row.contains(num_buttons) {
+                presses.insert(col);
+            }
+        }
+
+        // Pressing a free button means toggling every pivot button whose row
+        // includes it.
+        let null_space: Vec<BitSet> = (0..num_buttons)
+            .filter(|col| !pivot_cols.contains(col))
+            .map(|free_col| {
+                let mut vector = BitSet::new(num_buttons);
+                vector.insert(free_col);
+                for (row, &col) in rows.iter().zip(pivot_cols.iter()) {
+                    if row.contains(free_col) {
+                        vector.insert(col);
                     }
                 }
-                let free_variables: Vec<usize> = variable_is_free.iter()
-                    .enumerate()
-                    .filter_map(|(idx, &is_free)| if is_free { Some(idx) } else { None })
-                    .collect();
+                vector
+            })
+            .collect();
+
+        anyhow::ensure!(null_space.len() <= MAX_FREE_TOGGLES, "Too many free buttons to search: {}, but the most allowed is {}", null_space.len(), MAX_FREE_TOGGLES);
+        let mut fewest = presses.clone();
+        for combination in 1u64..(1 << null_space.len()) {
+            presses.toggle_in_place(&null_space[combination.trailing_zeros() as usize]);
+            if presses.count() < fewest.count() {
+                fewest = presses.clone();
+            }
+        }
+        Ok(Some(fewest))
+    }
+
+    /// Finds the fewest button presses that turn on the desired lights, by
+    /// breadth-first search over the states of the lights. Returns `None` if the
+    /// search runs out of states without finding them.
+    fn fewest_toggles_by_search(&self) -> Option<BitSet> {
+        let mut states: VecDeque<State> = VecDeque::new();
+        let no_lights = BitSet::new(self.joltages.len());
+        states.push_back(State {
+            lights: no_lights.clone(),
+            pressed_buttons: BitSet::new(self.buttons.len())
+        });
+        let mut seen_states: HashSet<BitSet> = HashSet::new();
+        seen_states.insert(no_lights);
+        while let Some(state) = states.pop_front() {
+            if state.lights == self.desired_state {
+                return Some(state.pressed_buttons);
+            }
+
+            for (button_ix, button) in self.buttons.iter().enumerate() {
+                let new_lights = state.lights.toggle(button);
+
+                // Check whether this button actually improves anything.
+                let wrong_lights = state.lights.toggle(&self.desired_state);
+                let state_better = button.intersects(&wrong_lights);
+
+                if state_better && seen_states.insert(new_lights.clone()) {
+                    let mut pressed_buttons = state.pressed_buttons.clone();
+                    pressed_buttons.insert(button_ix);
+                    states.push_back(State {
+                        lights: new_lights,
+                        pressed_buttons
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// No button can be pressed more times than the smallest joltage it affects.
+    fn max_presses(&self) -> Vec<i64> {
+        self.buttons
+            .iter()
+            .map(|button| self.joltages
+                .iter()
+                .enumerate()
+                .filter(|&(joltage_index, _)| button.contains(joltage_index))
+                .map(|(_, &joltage)| joltage as i64)
+                .min()
+                .unwrap_or(0))
+            .collect()
+    }
+
+    /// Finds the presses of each button that reach the joltages in the fewest
+    /// total presses, by modelling the machine as an integer program: one variable per
+    /// button, one equation per joltage, minimising the sum of the variables.
+    fn solve_with_cp_sat(&self) -> Result<Option<Vec<i64>>> {
+        let mut model = CpModelBuilder::default();
+        let presses: Vec<IntVar> = self.max_presses()
+            .into_iter()
+            .map(|max| model.new_int_var([(0, max)]))
+            .collect();
 
-                // Step 4: establish the constraints on the free variables.
-                let constraints: Vec<Constraint> = pivot_cols
+        for (joltage_index, &joltage) in self.joltages.iter().enumerate() {
+            let affecting_buttons: LinearExpr = self.buttons
+                .iter()
+                .zip(presses.iter())
+                .filter(|&(button, _)| button.contains(joltage_index))
+                .map(|(_, &var)| var)
+                .collect();
+            model.add_eq(affecting_buttons, joltage as i64);
+        }
+        model.minimize(presses.iter().copied().collect::<LinearExpr>());
+
+        // The machines are already solved in parallel, so give each one a single
+        // worker rather than one per core.
+        let params = SatParameters { num_search_workers: Some(1), ..Default::default() };
+        let response = model.solve_with_parameters(&params);
+        match response.status() {
+            CpSolverStatus::Optimal => Ok(Some(presses.iter().map(|var| var.solution_value(&response)).collect())),
+            CpSolverStatus::Infeasible => Ok(None),
+            status => anyhow::bail!("CP-SAT failed to solve a machine: {:?}", status)
+        }
+    }
+
+    /// Turns the buttons and joltages into a system of linear equations, and solves
+    /// it as far as possible.
+    fn joltage_system(&self) -> Result<JoltageSystem> {
+        let num_buttons = self.buttons.len();
+        let rows = self.joltages
+            .iter()
+            .enumerate()
+            .map(|(joltage_index, &joltage)| {
+                let mut row: Vec<i64> = self.buttons
                     .iter()
-                    .enumerate()
-                    .filter(|(_, opt)| opt.is_some())
-                    .flat_map(|(row_index, _)| {
-                        std::iter::once(Constraint {
-                            coefficients: &matrix[row_index],
-                            rhs: ConstraintRHS::IsPositive
-                        })
-                        .chain((lcm > 1).then(|| {
-                            Constraint {
-                                coefficients: &matrix[row_index],
-                                rhs: ConstraintRHS::DivisibleBy(lcm)
-                            }
-                        }))
-                    .chain(std::iter::once(Constraint {
-                        coefficients: &total_coefficients,
-                        rhs: ConstraintRHS::DivisibleBy(lcm)
-                    }))})
+                    .map(|button| button.contains(joltage_index) as i64)
                     .collect();
+                row.push(joltage as i64);
+                row
+            })
+            .collect();
+        let mut matrix = Matrix::new(rows)?;
+        let pivot_cols = matrix.row_reduce(num_buttons)?;
 
-                // Step 5: determine boundaries on the free variables from the IsPositive constraints.
-                let bounds = calculate_bounds(
-                    num_variables,
-                    &constraints,
-                    &free_variables
-                );
-
-                // Step 6: find the minimal non-negative assignment to the free variables
-                // that satisfies all constraints.
-                find_optimal_total(
-                    &constraints,
-                    &total_coefficients,
-                    lcm,
-                    &free_variables,
-                    &bounds
-                ) as u32
+        // Rows without a pivot say that 0 equals their last entry.
+        if (pivot_cols.len()..matrix.num_rows()).any(|row| matrix.row(row)[num_buttons] != 0) {
+            anyhow::bail!("The joltage equations are inconsistent");
+        }
+
+        let max_presses = self.max_presses();
+        let free_buttons: Vec<usize> = (0..num_buttons).filter(|button| !pivot_cols.contains(button)).collect();
+        let pivots: Vec<PivotEquation> = pivot_cols
+            .iter()
+            .enumerate()
+            .map(|(row_index, &button)| {
+                let row = matrix.row(row_index);
+                PivotEquation {
+                    button,
+                    divisor: row[button],
+                    constant: row[num_buttons],
+                    free_coefficients: free_buttons.iter().map(|&free_button| row[free_button]).collect(),
+                    max_presses: max_presses[button]
+                }
             })
-            .sum();
-        Ok(total_presses)
+            .collect();
+        let bounds = calculate_bounds(
+            free_buttons.iter().map(|&button| (0, max_presses[button])).collect(),
+            &pivots);
+
+        Ok(JoltageSystem {
+            free_buttons,
+            pivots,
+            bounds
+        })
     }
 }
 
-fn calculate_bounds(
-    num_variables: usize,
-    constraints: &[Constraint],
-    free_variables: &[usize],
-) -> Vec<(i16, i16)> {
-    // Start with widest possible bounds (use i64 internally to avoid overflow)
-    let mut bounds: Vec<(i64, i64)> = vec![(0, i16::MAX as i64); num_variables];
-
-    // Tighten bounds from IsPositive constraints. The bounds must be *sound* (never
-    // exclude valid solutions), so for each variable we use the best-case
-    // contributions of the other free variables (those that make the inequality
-    // easiest to satisfy).
-    for constraint in constraints {
-        if let ConstraintRHS::IsPositive = constraint.rhs {
-            let constant_term = *constraint.coefficients.last().unwrap() as i64;
-
-            // For each free variable, try to extract a bound
-            for &var_idx in free_variables.iter() {
-                let coeff = constraint.coefficients[var_idx] as i64;
-
-                if coeff == 0 {
+/// Tightens the bounds on the free buttons using the requirement that each pivot
+/// button is pressed between zero and its maximum number of times.
+///
+/// The bounds must be *sound* (never exclude valid solutions), so for each free
+/// button we use the best-case contributions of the others.
+fn calculate_bounds(mut bounds: Vec<(i64, i64)>, pivots: &[PivotEquation]) -> Vec<(i64, i64)> {
+    // Work in i128 so that none of this can overflow. Each pivot equation requires
+    // sum(coefficient * value) to be between `constant - divisor * max_presses`
+    // and `constant`.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pivot in pivots {
+            let low = pivot.constant as i128 - pivot.divisor as i128 * pivot.max_presses as i128;
+            let high = pivot.constant as i128;
+            let contributions: Vec<(i128, i128)> = pivot.free_coefficients
+                .iter()
+                .zip(bounds.iter())
+                .map(|(&coefficient, &(min, max))| {
+                    let a = coefficient as i128 * min as i128;
+                    let b = coefficient as i128 * max as i128;
+                    (a.min(b), a.max(b))
+                })
+                .collect();
+            let min_total: i128 = contributions.iter().map(|&(min, _)| min).sum();
+            let max_total: i128 = contributions.iter().map(|&(_, max)| max).sum();
+
+            for (free_index, &coefficient) in pivot.free_coefficients.iter().enumerate() {
+                if coefficient == 0 {
                     continue;
                 }
+                // This button's contribution must make up the rest of the range.
+                let coefficient = coefficient as i128;
+                let (own_min, own_max) = contributions[free_index];
+                let low_rest = low - (max_total - own_max);
+                let high_rest = high - (min_total - own_min);
+                let (lower, upper) = if coefficient > 0 {
+                    (Integer::div_ceil(&low_rest, &coefficient), Integer::div_floor(&high_rest, &coefficient))
+                } else {
+                    (Integer::div_ceil(&high_rest, &coefficient), Integer::div_floor(&low_rest, &coefficient))
+                };
 
-                // Constraint: constant + sum(coeff_i * x_i) >= 0
-                // For this variable: coeff * x_var >= -(constant + sum_others)
-                //
-                // Use the *helpful* extremes of the other variables so we never rule
-                // out a satisfiable assignment.
-                let mut best_others = constant_term;
-
-                for &other_var_idx in free_variables.iter() {
-                    if other_var_idx == var_idx {
-                        continue;
-                    }
-                    let other_coeff = constraint.coefficients[other_var_idx] as i64;
-                    let (other_min, other_max) = bounds[other_var_idx];
-
-                    if other_coeff > 0 {
-                        best_others = best_others
-                            .saturating_add(other_coeff.saturating_mul(other_max));
-                    } else if other_coeff < 0 {
-                        best_others = best_others
-                            .saturating_add(other_coeff.saturating_mul(other_min));
-                    }
+                let (min, max) = &mut bounds[free_index];
+                if lower > *min as i128 {
+                    *min = lower.min(i64::MAX as i128) as i64;
+                    changed = true;
                 }
-
-                // Now isolate this variable
-                if coeff > 0 {
-                    // x_var >= ceil(-best_others / coeff)
-                    let numerator = -best_others;
-                    let lower = ((numerator + coeff - 1) / coeff).max(0);
-                    if lower > bounds[var_idx].0 {
-                        bounds[var_idx].0 = lower;
-                    }
-                } else {
-                    // x_var <= floor(-best_others / coeff)  (coeff < 0)
-                    let numerator = -best_others;
-                    let upper = numerator / coeff;
-                    if upper < bounds[var_idx].1 {
-                        bounds[var_idx].1 = upper;
-                    }
+                if upper < *max as i128 {
+                    *max = upper.max(i64::MIN as i128) as i64;
+                    changed = true;
                 }
             }
+
+            // An inverted bound simply yields an empty range in the search, so
+            // there's no point tightening any further.
+            if bounds.iter().any(|&(min, max)| min > max) {
+                return bounds;
+            }
         }
     }
 
     bounds
-        .into_iter()
-        .map(|(lo, hi)| {
-            // Clamp back into i16 space; an inverted bound simply yields an empty
-            // range in the search.
-            let lo = lo.clamp(0, i16::MAX as i64) as i16;
-            let hi = hi.clamp(0, i16::MAX as i64) as i16;
-            (lo, hi)
-        })
-        .collect()
 }
 
-fn find_optimal_total(
-    constraints: &[Constraint],
-    coefficients: &[i16],
-    lcm: i16,
-    free_variables: &[usize],
-    bounds: &[(i16, i16)]
-) -> i16 {
-
-    let mut best_total = i16::MAX;
-
-    fn search(
-        free_var_idx: usize,
-        free_variable_values: &mut Vec<i16>,
-        constraints: &[Constraint],
-        coefficients: &[i16],
-        lcm: i16,
-        free_variables: &[usize],
-        bounds: &[(i16, i16)],
-        best_total: &mut i16,
-    ) {
-        // Base case: all free variables assigned
-        if free_var_idx == free_variables.len() {
-            // Check constraints
-            if !constraints.iter().all(|c| c.is_satisfied(free_variable_values)) {
-                return;
-            }
+impl JoltageSystem {
+    /// Finds the presses of each button that reach the joltages in the fewest
+    /// total presses, if there are any.
+    fn find_optimal_presses(&self) -> Result<Option<Vec<i64>>> {
+        if self.bounds.iter().any(|&(min, max)| min > max) {
+            return Ok(None);
+        }
+        let mut search = BranchAndBound::new(self)?;
+        search.search(0, 0);
+        Ok(search.best_presses)
+    }
+}
 
-            // Calculate total
-            let total: i16 = coefficients
-                .iter()
-                .zip(free_variable_values.iter())
-                .map(|(c, v)| c * v)
-                .sum::<i16>()
-                / lcm;
+/// A depth-first search over the free buttons' presses, one button per level,
+/// that cuts off any subtree which can't satisfy every pivot equation or can't
+/// beat the best total found so far.
+///
+/// Substituting the pivot equations into the total number of presses leaves it
+/// as a linear function of the free buttons, with rational coefficients. Scaling
+/// everything by the LCM of the pivot divisors keeps it in the integers, and
+/// gives an exact lower bound on the total within the remaining box of bounds.
+struct BranchAndBound<'a> {
+    system: &'a JoltageSystem,
+    /// `constant - sum(coefficient * presses)` for each pivot equation, over the
+    /// free buttons assigned so far.
+    numerators: Vec<i128>,
+    /// For each level and pivot, the range that the free buttons from that
+    /// level onwards can subtract from the numerator.
+    remaining_ranges: Vec<Vec<(i128, i128)>>,
+    /// The LCM of the pivot divisors.
+    scale: i128,
+    /// The scaled total's coefficient for each free button.
+    weights: Vec<i128>,
+    /// For each level, the smallest scaled contribution of the free buttons from
+    /// that level onwards.
+    remaining_minimum: Vec<i128>,
+    /// The presses of each free button at the current point in the search.
+    values: Vec<i64>,
+    best_total: Option<i64>,
+    /// The presses of every button in the best solution so far.
+    best_presses: Option<Vec<i64>>
+}
 
-            if total < *best_total {
-                *best_total = total;
+impl<'a> BranchAndBound<'a> {
+    fn new(system: &'a JoltageSystem) -> Result<Self> {
+        let overflow = "Integer overflow preparing the joltage search";
+        let num_free = system.free_buttons.len();
+        let scale = system.pivots
+            .iter()
+            .fold(1, |lcm: i128, pivot| lcm.lcm(&(pivot.divisor as i128)));
+        let weights = (0..num_free)
+            .map(|free_index| system.pivots.iter().try_fold(scale, |weight, pivot| {
+                (pivot.free_coefficients[free_index] as i128)
+                    .checked_mul(scale / pivot.divisor as i128)
+                    .and_then(|reduction| weight.checked_sub(reduction))
+            }))
+            .collect::<Option<Vec<i128>>>()
+            .context(overflow)?;
+
+        // Work out the ranges from the last level backwards, checking the sums
+        // as we go. Everything the search itself calculates is bounded by these,
+        // so it can use unchecked arithmetic.
+        let mut remaining_ranges: Vec<Vec<(i128, i128)>> = vec![vec![(0, 0); system.pivots.len()]; num_free + 1];
+        let mut remaining_minimum: Vec<i128> = vec![0; num_free + 1];
+        for level in (0..num_free).rev() {
+            let (min, max) = system.bounds[level];
+            for (pivot_index, pivot) in system.pivots.iter().enumerate() {
+                let coefficient = pivot.free_coefficients[level] as i128;
+                let (a, b) = (coefficient * min as i128, coefficient * max as i128);
+                let (low, high) = remaining_ranges[level + 1][pivot_index];
+                remaining_ranges[level][pivot_index] = low
+                    .checked_add(a.min(b))
+                    .zip(high.checked_add(a.max(b)))
+                    .context(overflow)?;
             }
+            let weight = weights[level];
+            remaining_minimum[level] = weight
+                .checked_mul(min as i128)
+                .zip(weight.checked_mul(max as i128))
+                .and_then(|(a, b)| remaining_minimum[level + 1].checked_add(a.min(b)))
+                .context(overflow)?;
+        }
+        let mut extreme: i128 = system.bounds.iter().map(|&(min, max)| min.unsigned_abs().max(max.unsigned_abs()) as i128).sum();
+        extreme = extreme.checked_add(remaining_minimum[0].abs()).context(overflow)?;
+        for (pivot, &(low, high)) in system.pivots.iter().zip(remaining_ranges[0].iter()) {
+            let numerator_extreme = (pivot.constant as i128).abs() + low.abs().max(high.abs());
+            extreme = extreme.checked_add(numerator_extreme).context(overflow)?;
+        }
+        extreme.checked_mul(scale).context(overflow)?;
+
+        Ok(Self {
+            system,
+            numerators: system.pivots.iter().map(|pivot| pivot.constant as i128).collect(),
+            remaining_ranges,
+            scale,
+            weights,
+            remaining_minimum,
+            values: vec![0; num_free],
+            best_total: None,
+            best_presses: None
+        })
+    }
+
+    /// Whether every pivot button could still be pressed between zero and its
+    /// maximum number of times, given the free buttons from `level` onwards.
+    fn is_feasible(&self, level: usize) -> bool {
+        self.system.pivots
+            .iter()
+            .zip(self.numerators.iter())
+            .zip(self.remaining_ranges[level].iter())
+            .all(|((pivot, &numerator), &(low, high))| {
+                numerator - low >= 0 && numerator - high <= pivot.divisor as i128 * pivot.max_presses as i128
+            })
+    }
+
+    /// The scaled total of any solution below this point can't be less than this.
+    fn lower_bound(&self, level: usize, free_total: i128) -> i128 {
+        let pivot_total: i128 = self.system.pivots
+            .iter()
+            .zip(self.numerators.iter())
+            .map(|(pivot, &numerator)| numerator * (self.scale / pivot.divisor as i128))
+            .sum();
+        free_total * self.scale + pivot_total + self.remaining_minimum[level]
+    }
+
+    fn search(&mut self, level: usize, free_total: i128) {
+        if level == self.system.free_buttons.len() {
+            self.record_solution(free_total);
             return;
         }
 
-        // Get the actual variable index and its contribution
-        let var_idx = free_variables[free_var_idx];
-        let (min_val, max_val) = bounds[var_idx];
-
-        if coefficients[var_idx] < 0 {
-            // Loop backwards for negative contribution
-            for val in (min_val..=max_val).rev() {
-                free_variable_values[var_idx] = val;
-                search(
-                    free_var_idx + 1,
-                    free_variable_values,
-                    constraints,
-                    coefficients,
-                    lcm,
-                    free_variables,
-                    bounds,
-                    best_total,
-                );
+        // Try the values in the order that keeps the total lowest, so that once
+        // the bound rules one out, it rules out all the rest too.
+        let (min, max) = self.system.bounds[level];
+        let ascending = self.weights[level] >= 0;
+        for step in 0..=(max - min) {
+            let value = if ascending { min + step } else { max - step };
+            self.values[level] = value;
+            for (numerator, pivot) in self.numerators.iter_mut().zip(self.system.pivots.iter()) {
+                *numerator -= pivot.free_coefficients[level] as i128 * value as i128;
             }
-        } else {
-            // Loop forwards for positive or zero contribution
-            for val in min_val..=max_val {
-                free_variable_values[var_idx] = val;
-                search(
-                    free_var_idx + 1,
-                    free_variable_values,
-                    constraints,
-                    coefficients,
-                    lcm,
-                    free_variables,
-                    bounds,
-                    best_total,
-                );
+
+            let new_free_total = free_total + value as i128;
+            let bound = self.lower_bound(level + 1, new_free_total);
+            let beaten = self.best_total.is_some_and(|best| bound >= best as i128 * self.scale);
+            if !beaten && self.is_feasible(level + 1) {
+                self.search(level + 1, new_free_total);
+            }
+
+            for (numerator, pivot) in self.numerators.iter_mut().zip(self.system.pivots.iter()) {
+                *numerator += pivot.free_coefficients[level] as i128 * value as i128;
+            }
+            if beaten {
+                break;
             }
         }
     }
 
-    let mut free_variable_values = vec![0; coefficients.len()];
-    free_variable_values[coefficients.len() - 1] = 1;  // Set RHS position to 1
+    /// With every free button assigned, checks that each pivot button gets a
+    /// whole number of presses, and keeps the total if it's the best so far.
+    fn record_solution(&mut self, free_total: i128) {
+        let mut total = free_total;
+        for (pivot, &numerator) in self.system.pivots.iter().zip(self.numerators.iter()) {
+            let divisor = pivot.divisor as i128;
+            if numerator < 0 || numerator % divisor != 0 {
+                return;
+            }
+            total += numerator / divisor;
+        }
+        if self.best_total.is_none_or(|best| total < best as i128) {
+            let mut presses = vec![0; self.system.free_buttons.len() + self.system.pivots.len()];
+            for (&button, &value) in self.system.free_buttons.iter().zip(self.values.iter()) {
+                presses[button] = value;
+            }
+            for (pivot, &numerator) in self.system.pivots.iter().zip(self.numerators.iter()) {
+                presses[pivot.button] = (numerator / pivot.divisor as i128) as i64;
+            }
+            self.best_total = Some(total as i64);
+            self.best_presses = Some(presses);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machines with more buttons than joltages, so row reduction leaves some
+    /// buttons free.
+    const FREE_BUTTON_MACHINES: [&str; 4] = [
+        "[##] (0) (1) (0,1) {3,5}",
+        "[#.#.] (0,1) (1,2) (2,3) (0,3) (0,1,2,3) {5,7,6,4}",
+        "[...] (0) (1) (2) (0,1) (1,2) (0,2) (0,1,2) {6,9,7}",
+        "[#..#.] (0,1,2) (2,3,4) (0,4) (1,3) (0,1,2,3,4) (3) {20,31,25,40,18}",
+    ];
+
+    #[test]
+    fn example_part_1_cross_checks() {
+        let day = Day10 { lights_solver: LightsSolver::CrossCheck, solver: Solver::Search, report: false };
+        let (presses, _) = day.execute_part_1(day.example_input().unwrap()).unwrap();
+        assert_eq!(presses, 7);
+    }
+
+    #[test]
+    fn too_many_free_lights_buttons() {
+        let buttons = (0..=MAX_FREE_TOGGLES + 1).map(|_| "(0)").join(" ");
+        let machine = Machine::parse(&format!("[#] {} {{1}}", buttons)).unwrap();
+        assert!(machine.fewest_toggles().is_err());
+    }
 
-    search(
-        0,
-        &mut free_variable_values,
-        constraints,
-        coefficients,
-        lcm,
-        free_variables,
-        bounds,
-        &mut best_total,
-    );
+    #[test]
+    fn example_part_2_cross_checks() {
+        let day = Day10 { lights_solver: LightsSolver::Elimination, solver: Solver::CrossCheck, report: false };
+        let input = day.example_input().unwrap();
+        let (_, ctx) = day.execute_part_1(input).unwrap();
+        assert_eq!(day.execute_part_2(input, ctx).unwrap(), 33);
+    }
 
-    best_total
+    #[test]
+    fn joltage_solvers_agree_on_free_buttons() {
+        for line in FREE_BUTTON_MACHINES {
+            let machine = Machine::parse(line).unwrap();
+            let system = machine.joltage_system().unwrap();
+            assert!(!system.free_buttons.is_empty(), "{} has no free buttons", line);
+
+            let search = system.find_optimal_presses().unwrap().unwrap();
+            let cp_sat = machine.solve_with_cp_sat().unwrap().unwrap();
+            machine.verify_presses(&search).unwrap();
+            machine.verify_presses(&cp_sat).unwrap();
+            assert_eq!(search.iter().sum::<i64>(), cp_sat.iter().sum::<i64>(), "Solvers disagree on {}", line);
+        }
+    }
 }
@@ -1,61 +1,76 @@
-use advent_of_code_rust_runner::{DayImplementation, Result};
+use advent_of_code_rust_runner::{DayImplementation, Result, Context};
 use std::collections::{HashMap, VecDeque};
+use itertools::Itertools;
 
-pub struct Day11;
-
-pub struct Day11Context {
-    devices: Vec<Device>,
-    ordering: Vec<usize>,
-}
-
-const OUT_DEVICE_INDEX: usize = 0;
-const YOU_DEVICE_INDEX: usize = 1;
-const SVR_DEVICE_INDEX: usize = 2;
-const DAC_DEVICE_INDEX: usize = 3;
-const FFT_DEVICE_INDEX: usize = 4;
-const NUM_FIXED_DEVICES: usize = 5;
-const FIXED_DEVICES: [&str; NUM_FIXED_DEVICES] = [
-    "out",
-    "you",
-    "svr",
-    "dac",
-    "fft"
-];
-const SEEN_NEITHER: usize = 0;
-const SEEN_DAC: usize = 1;
-const SEEN_FFT: usize = 2;
-const SEEN_BOTH: usize = 3;
-const NUM_SEEN_STATES: usize = 4;
+pub struct Day11 {
+    pub part_1: Route,
+    pub part_2: Route,
+    /// Allow cycles in the graph, as long as no route's starting device can
+    /// reach one. Otherwise any cycle is an error.
+    pub allow_unreachable_cycles: bool
+}
 
+/// Which paths through the devices to count, by device name.
 #[derive(Clone)]
-struct Device {
-    id: usize,
-    outputs: Option<Vec<usize>>,
-    direct_routes_in: usize,
-    paths_to_node: [u64; NUM_SEEN_STATES], // Bitmask for seen special nodes
+pub struct Route {
+    pub from: String,
+    pub to: String,
+    /// Devices that every path must pass through, in any order.
+    pub via: Vec<String>,
+    /// Devices that no path may pass through.
+    pub avoiding: Vec<String>
 }
 
-impl Device {
-    fn new(id: usize) -> Self {
-        Self {
-            id,
-            outputs: None,
-            direct_routes_in: 0,
-            paths_to_node: [0u64; NUM_SEEN_STATES],
-        }
+impl Route {
+    pub fn part_1() -> Route {
+        Route { from: "you".to_string(), to: "out".to_string(), via: Vec::new(), avoiding: Vec::new() }
     }
 
-    fn reset(&mut self) {
-        self.paths_to_node = [0u64; NUM_SEEN_STATES];
+    pub fn part_2() -> Route {
+        Route { from: "svr".to_string(), to: "out".to_string(), via: vec!["dac".to_string(), "fft".to_string()], avoiding: Vec::new() }
     }
 }
 
-impl DayImplementation for Day11 {
-    type Output<'a> = u64;
-    type Context<'a> = Day11Context;
+/// Written as the devices to go from and to, optionally followed by `via` and
+/// `avoiding` with comma-separated devices, e.g. `svr out via dac,fft avoiding ggg`.
+impl std::str::FromStr for Route {
+    type Err = anyhow::Error;
 
-    fn day(&self) -> u8 { 11 }
-    fn example_input(&self) -> Option<&'static str> { Some("aaa: you hhh
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_ascii_whitespace();
+        let (Some(from), Some(to)) = (words.next(), words.next()) else {
+            anyhow::bail!("'{}' is not a route; expected the devices to go from and to", s);
+        };
+        let mut route = Route { from: from.to_string(), to: to.to_string(), via: Vec::new(), avoiding: Vec::new() };
+        while let Some(keyword) = words.next() {
+            let devices = match keyword {
+                "via" => &mut route.via,
+                "avoiding" => &mut route.avoiding,
+                _ => anyhow::bail!("'{}' is not a route; expected via or avoiding, found '{}'", s, keyword)
+            };
+            let names = words.next().with_context(|| format!("'{}' is not a route; expected devices after {}", s, keyword))?;
+            devices.extend(names.split(',').map(str::to_string));
+        }
+        Ok(route)
+    }
+}
+
+/// The most devices a route can require. Every device tracks its paths separately
+/// for each set of required devices seen so far, so this is 2^20 counts each.
+const MAX_REQUIRED_DEVICES: usize = 20;
+
+pub struct Day11Context<'a> {
+    devices: Vec<Device>,
+    device_indices: HashMap<&'a str, usize>,
+    /// The order of every device, or `None` if each route is ordered separately
+    /// because the graph may have cycles.
+    ordering: Option<Vec<usize>>,
+}
+
+/// An input can give part 2 a graph of its own after a blank line, as the example
+/// does: the first example has no route from `svr`. Otherwise both parts use the
+/// same graph.
+static EXAMPLE_INPUT: &str = "aaa: you hhh
 you: bbb ccc
 bbb: ddd eee
 ccc: ddd eee fff
@@ -64,29 +79,9 @@ eee: out
 fff: out
 ggg: out
 hhh: ccc fff iii
-iii: out") }
-    fn example_part_1_result(&self) -> Option<Self::Output<'static>> { Some(5) }
-    fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(2) }
-
-    fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
-        let mut devices = parse_input(input);
-        let ordering = generate_ordered_graph(&devices);
-        let number_of_paths = count_paths(
-            &mut devices,
-            &ordering,
-            YOU_DEVICE_INDEX,
-            OUT_DEVICE_INDEX,
-            None);
-        devices.iter_mut().for_each(|d| d.reset());
+iii: out
 
-        Ok((number_of_paths, Day11Context { devices, ordering }))
-    }
-
-    fn execute_part_2<'a>(&self, input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
-        // Annoyingly, my runner doesn't support different inputs for parts
-        // 1 and 2, so we have to detect the example input here and replace it.
-        let (mut devices, ordering) = if input.len() < 200 {
-            let devices = parse_input("svr: aaa bbb
+svr: aaa bbb
 aaa: fft
 fft: ccc
 bbb: tty
@@ -98,73 +93,151 @@ eee: dac
 dac: fff
 fff: ggg hhh
 ggg: out
-hhh: out");
-            let ordering = generate_ordered_graph(&devices);
-            (devices, ordering)
+hhh: out";
+
+#[derive(Clone)]
+struct Device {
+    outputs: Option<Vec<usize>>,
+}
+
+impl Device {
+    fn new() -> Self {
+        Self {
+            outputs: None,
+        }
+    }
+
+    fn outputs(&self) -> &[usize] {
+        self.outputs.as_deref().unwrap_or_default()
+    }
+}
+
+/// What a device means for a route: bit `i` of a seen-state is set once a path
+/// has passed through `via[i]`.
+#[derive(Clone, Copy)]
+enum Waypoint {
+    Ordinary,
+    Required(usize),
+    Forbidden
+}
+
+impl DayImplementation for Day11 {
+    type Output<'a> = u64;
+    type Context<'a> = Day11Context<'a>;
+
+    fn day(&self) -> u8 { 11 }
+    fn example_input(&self) -> Option<&'static str> { Some(EXAMPLE_INPUT) }
+    fn example_part_1_result(&self) -> Option<Self::Output<'static>> { Some(5) }
+    fn example_part_2_result(&self) -> Option<Self::Output<'static>> { Some(2) }
+
+    fn execute_part_1<'a>(&self, input: &'a str) -> Result<(Self::Output<'a>, Self::Context<'a>)> {
+        let (part_1_input, _) = split_graphs(input);
+        let ctx = self.build_context(part_1_input)?;
+        let number_of_paths = count_paths(&ctx, &self.part_1)?;
+
+        Ok((number_of_paths, ctx))
+    }
+
+    fn execute_part_2<'a>(&self, input: &'a str, ctx: Self::Context<'a>) -> Result<Self::Output<'a>> {
+        let ctx = match split_graphs(input) {
+            (_, Some(part_2_input)) => self.build_context(part_2_input).context("Invalid graph for part 2")?,
+            (_, None) => ctx
+        };
+
+        count_paths(&ctx, &self.part_2)
+    }
+}
+
+impl Day11 {
+    fn build_context<'a>(&self, input: &'a str) -> Result<Day11Context<'a>> {
+        let (devices, device_indices) = parse_input(input)?;
+        let ordering = if self.allow_unreachable_cycles {
+            None
         } else {
-            (ctx.devices, ctx.ordering)
+            Some(generate_ordered_graph(&devices, &device_indices, None)?)
         };
+        Ok(Day11Context { devices, device_indices, ordering })
+    }
+}
 
-        Ok(count_paths(
-            &mut devices,
-            &ordering,
-            SVR_DEVICE_INDEX,
-            OUT_DEVICE_INDEX,
-            Some(SEEN_BOTH)))
+/// Splits the input at its first blank line, into part 1's graph and part 2's
+/// own graph if it has one.
+fn split_graphs(input: &str) -> (&str, Option<&str>) {
+    let split = input.split_once("\n\n").or_else(|| input.split_once("\r\n\r\n"));
+    match split {
+        Some((part_1_input, part_2_input)) if !part_2_input.trim().is_empty() => (part_1_input, Some(part_2_input)),
+        Some((part_1_input, _)) => (part_1_input, None),
+        None => (input, None)
     }
 }
 
-fn parse_input(input: &str) -> Vec<Device> {
+fn parse_input(input: &str) -> Result<(Vec<Device>, HashMap<&str, usize>)> {
     let lines = input.lines().collect::<Vec<&str>>();
     let mut devices: Vec<Device> = Vec::with_capacity(lines.len());
     let mut devices_dict: HashMap<&str, usize> = HashMap::with_capacity(lines.len());
 
-    for (i, &device_name) in FIXED_DEVICES.iter().enumerate() {
-        devices_dict.insert(device_name, i);
-        devices.push(Device::new(i));
-    }
-
-    for line in lines.iter() {
+    for (line_ix, line) in lines.iter().enumerate() {
         let mut parts = line.split_ascii_whitespace();
 
-        let device_name = parts.next().unwrap().trim_end_matches(":");
+        let device_name = parts
+            .next()
+            .with_context(|| format!("Invalid input on line {}", line_ix + 1))?
+            .trim_end_matches(":");
         let outputs = Some(
             parts
             .map(|output_name| devices_dict
                 .entry(output_name)
                 .or_insert_with(|| {
                     let new_index = devices.len();
-                    devices.push(Device::new(new_index));
+                    devices.push(Device::new());
                     new_index
                 })
                 .to_owned())
             .collect::<Vec<usize>>());
 
-        for &output_index in outputs.as_ref().unwrap().iter() {
-            devices[output_index].direct_routes_in += 1;
-        }
-
         if let Some(device_index) = devices_dict.get(device_name) {
             devices[*device_index].outputs = outputs;
         } else {
             let new_index = devices.len();
             devices_dict.insert(device_name, new_index);
-            let mut device = Device::new(new_index);
+            let mut device = Device::new();
             device.outputs = outputs;
             devices.push(device);
         }
     }
-    devices
+    Ok((devices, devices_dict))
 }
 
-fn generate_ordered_graph(devices: &[Device]) -> Vec<usize> {
+/// Sorts the devices reachable from `start`, or every device if there's no
+/// start, so that each comes before all of its outputs. Fails if any of those
+/// devices form a cycle, as there would then be infinitely many paths.
+fn generate_ordered_graph(devices: &[Device], device_indices: &HashMap<&str, usize>, start: Option<usize>) -> Result<Vec<usize>> {
+    let mut included = vec![start.is_none(); devices.len()];
+    if let Some(start) = start {
+        included[start] = true;
+        let mut queue = VecDeque::from([start]);
+        while let Some(device_index) = queue.pop_front() {
+            for &output_index in devices[device_index].outputs() {
+                if !included[output_index] {
+                    included[output_index] = true;
+                    queue.push_back(output_index);
+                }
+            }
+        }
+    }
+
     // Kahn's algorithm for topological sorting
-    let mut indegrees: Vec<usize> = devices.iter().map(|d| d.direct_routes_in).collect();
+    let mut indegrees: Vec<usize> = vec![0; devices.len()];
+    for (_, device) in devices.iter().enumerate().filter(|&(i, _)| included[i]) {
+        for &output_index in device.outputs() {
+            indegrees[output_index] += 1;
+        }
+    }
     let mut queue: VecDeque<usize> = VecDeque::new();
     let mut ordered_graph: Vec<usize> = Vec::with_capacity(devices.len());
 
     for (i, &indegree) in indegrees.iter().enumerate() {
-        if indegree == 0 {
+        if included[i] && indegree == 0 {
             queue.push_back(i);
         }
     }
@@ -172,66 +245,144 @@ fn generate_ordered_graph(devices: &[Device]) -> Vec<usize> {
     while let Some(device_index) = queue.pop_front() {
         ordered_graph.push(device_index);
 
-        let device = &devices[device_index];
-        if let Some(outputs) = &device.outputs {
-            for &output_index in outputs.iter() {
-                indegrees[output_index] -= 1;
-                if indegrees[output_index] == 0 {
-                    queue.push_back(output_index);
-                }
+        for &output_index in devices[device_index].outputs() {
+            indegrees[output_index] -= 1;
+            if indegrees[output_index] == 0 {
+                queue.push_back(output_index);
             }
         }
     }
 
-    ordered_graph
+    if ordered_graph.len() < included.iter().filter(|&&included| included).count() {
+        let names: HashMap<usize, &str> = device_indices.iter().map(|(&name, &index)| (index, name)).collect();
+        let cycle = find_cycle(devices, &indegrees)
+            .iter()
+            .map(|index| names[index])
+            .join(" -> ");
+        anyhow::bail!("Devices form a cycle: {}", cycle);
+    }
+
+    Ok(ordered_graph)
 }
 
-fn visit_device(devices: &mut [Device], device_index: usize) {
-    let device = &mut devices[device_index];
-    if device.id == DAC_DEVICE_INDEX {
-        assert!(device.paths_to_node[SEEN_DAC] == 0);
-        assert!(device.paths_to_node[SEEN_BOTH] == 0);
-        device.paths_to_node[SEEN_BOTH] += device.paths_to_node[SEEN_FFT];
-        device.paths_to_node[SEEN_FFT] = 0;
-        device.paths_to_node[SEEN_DAC] += device.paths_to_node[SEEN_NEITHER];
-        device.paths_to_node[SEEN_NEITHER] = 0;
-    } else if device.id == FFT_DEVICE_INDEX {
-        assert!(device.paths_to_node[SEEN_FFT] == 0);
-        assert!(device.paths_to_node[SEEN_BOTH] == 0);
-        device.paths_to_node[SEEN_BOTH] += device.paths_to_node[SEEN_DAC];
-        device.paths_to_node[SEEN_DAC] = 0;
-        device.paths_to_node[SEEN_FFT] += device.paths_to_node[SEEN_NEITHER];
-        device.paths_to_node[SEEN_NEITHER] = 0;
+/// Finds a cycle among the devices that Kahn's algorithm couldn't order, which
+/// are those left with a positive indegree. Each of them still has an input from
+/// another of them, so following inputs backwards must eventually repeat.
+/// Returns the devices in the cycle in order, starting and ending with the same
+/// one.
+fn find_cycle(devices: &[Device], indegrees: &[usize]) -> Vec<usize> {
+    let mut inputs: Vec<Vec<usize>> = vec![Vec::new(); devices.len()];
+    for (device_index, device) in devices.iter().enumerate().filter(|&(i, _)| indegrees[i] > 0) {
+        for &output_index in device.outputs() {
+            inputs[output_index].push(device_index);
+        }
     }
 
-    let device = device.clone();
+    let mut walk: Vec<usize> = Vec::new();
+    let mut position: Vec<Option<usize>> = vec![None; devices.len()];
+    let mut device_index = indegrees.iter().position(|&indegree| indegree > 0).unwrap();
+    while position[device_index].is_none() {
+        position[device_index] = Some(walk.len());
+        walk.push(device_index);
+        device_index = inputs[device_index][0];
+    }
 
-    if let Some(outputs) = &device.outputs {
-        for &output_index in outputs.iter() {
-            let output_device = &mut devices[output_index];
-            for seen_state in 0..NUM_SEEN_STATES {
-                output_device.paths_to_node[seen_state] += device.paths_to_node[seen_state];
+    let mut cycle = walk.split_off(position[device_index].unwrap());
+    cycle.push(device_index);
+    cycle.reverse();
+    cycle
+}
+
+/// Passes the paths reaching a device on to each of its outputs, after
+/// accounting for the device itself.
+fn visit_device(devices: &[Device], waypoints: &[Waypoint], paths_to_node: &mut [Vec<u64>], device_index: usize) {
+    match waypoints[device_index] {
+        Waypoint::Ordinary => {},
+        Waypoint::Required(bit) => {
+            // In a DAG, no path has seen this device before reaching it.
+            let seen = &mut paths_to_node[device_index];
+            for seen_state in 0..seen.len() {
+                if seen_state & (1 << bit) == 0 {
+                    seen[seen_state | (1 << bit)] += seen[seen_state];
+                    seen[seen_state] = 0;
+                }
             }
+        },
+        Waypoint::Forbidden => paths_to_node[device_index].fill(0),
+    }
+
+    let paths = paths_to_node[device_index].clone();
+    for &output_index in devices[device_index].outputs() {
+        for (output_paths, &paths) in paths_to_node[output_index].iter_mut().zip(paths.iter()) {
+            *output_paths += paths;
         }
     }
 }
 
-fn count_paths(
-    devices: &mut [Device],
-    ordering: &[usize],
-    from_idx: usize,
-    to_idx: usize,
-    seen_requirement: Option<usize>
-) -> u64 {
-    devices[from_idx].paths_to_node[SEEN_NEITHER] = 1;
+/// Counts the paths from one device to another that pass through every
+/// required device and none of the forbidden ones. Each device tracks the
+/// number of paths reaching it for each of the 2^k sets of required devices
+/// seen on the way.
+fn count_paths(ctx: &Day11Context, route: &Route) -> Result<u64> {
+    let device_index = |name: &str| ctx.device_indices
+        .get(name)
+        .copied()
+        .with_context(|| format!("No device called '{}'", name));
+    anyhow::ensure!(route.via.iter().all_unique(), "Required devices must be distinct");
+    anyhow::ensure!(route.via.len() <= MAX_REQUIRED_DEVICES, "Too many required devices: {}, but the most allowed is {}", route.via.len(), MAX_REQUIRED_DEVICES);
 
+    let mut waypoints = vec![Waypoint::Ordinary; ctx.devices.len()];
+    for (bit, name) in route.via.iter().enumerate() {
+        waypoints[device_index(name)?] = Waypoint::Required(bit);
+    }
+    for name in route.avoiding.iter() {
+        waypoints[device_index(name)?] = Waypoint::Forbidden;
+    }
+
+    let num_seen_states = 1 << route.via.len();
+    let mut paths_to_node = vec![vec![0u64; num_seen_states]; ctx.devices.len()];
+    let from_idx = device_index(&route.from)?;
+    let to_idx = device_index(&route.to)?;
+    paths_to_node[from_idx][0] = 1;
+
+    let route_ordering;
+    let ordering = match &ctx.ordering {
+        Some(ordering) => ordering,
+        None => {
+            route_ordering = generate_ordered_graph(&ctx.devices, &ctx.device_indices, Some(from_idx))?;
+            &route_ordering
+        }
+    };
     for &device_index in ordering.iter() {
-        visit_device(devices, device_index);
+        visit_device(&ctx.devices, &waypoints, &mut paths_to_node, device_index);
+    }
+
+    Ok(paths_to_node[to_idx][num_seen_states - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day() -> Day11 {
+        Day11 { part_1: Route::part_1(), part_2: Route::part_2(), allow_unreachable_cycles: false }
     }
 
-    if let Some(seen_state) = seen_requirement {
-        devices[to_idx].paths_to_node[seen_state]
-    } else {
-        devices[to_idx].paths_to_node.iter().sum()
+    #[test]
+    fn example_parts_use_their_own_graphs() {
+        let day = day();
+        let (paths, ctx) = day.execute_part_1(EXAMPLE_INPUT).unwrap();
+        assert_eq!(paths, 5);
+        assert_eq!(day.execute_part_2(EXAMPLE_INPUT, ctx).unwrap(), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn single_graph_is_shared_by_both_parts() {
+        let (_, part_2_input) = split_graphs(EXAMPLE_INPUT);
+        let input = format!("{}\nyou: svr\n", part_2_input.unwrap());
+        let day = day();
+        let (paths, ctx) = day.execute_part_1(&input).unwrap();
+        assert_eq!(paths, 8);
+        assert_eq!(day.execute_part_2(&input, ctx).unwrap(), 2);
+    }
+}
@@ -10,8 +10,20 @@ mod day09;
 mod day10;
 mod day11;
 mod day12;
+mod matrix;
 
 use advent_of_code_rust_runner::{Runner, Day};
+use std::str::FromStr;
+
+/// Reads a day's option from an environment variable, such as
+/// `DAY07_COUNTING=bigint`. Returns `None` if it isn't set.
+fn option_from_env<T: FromStr<Err: std::fmt::Display>>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    Some(value.parse().unwrap_or_else(|e| {
+        eprintln!("Invalid {name}: {e}");
+        std::process::exit(1);
+    }))
+}
 
 fn main() {
     env_logger::Builder::new()
@@ -27,11 +39,26 @@ fn main() {
         Box::new(day04::Day04 {}),
         Box::new(day05::Day05 {}),
         Box::new(day06::Day06 {}),
-        Box::new(day07::Day07 {}),
-        Box::new(day08::Day08 {}),
-        Box::new(day09::Day09 {}),
-        Box::new(day10::Day10 {}),
-        Box::new(day11::Day11 {}),
+        Box::new(day07::Day07 {
+            counting: option_from_env("DAY07_COUNTING").unwrap_or(day07::Counting::Checked),
+            render: option_from_env("DAY07_RENDER")
+        }),
+        Box::new(day08::Day08 {
+            connections: option_from_env("DAY08_CONNECTIONS"),
+            report: option_from_env("DAY08_REPORT").unwrap_or(false),
+            export: option_from_env("DAY08_EXPORT")
+        }),
+        Box::new(day09::Day09 { svg: option_from_env("DAY09_SVG"), top: option_from_env("DAY09_TOP") }),
+        Box::new(day10::Day10 {
+            lights_solver: option_from_env("DAY10_LIGHTS_SOLVER").unwrap_or(day10::LightsSolver::Elimination),
+            solver: option_from_env("DAY10_SOLVER").unwrap_or(day10::Solver::Search),
+            report: option_from_env("DAY10_REPORT").unwrap_or(false)
+        }),
+        Box::new(day11::Day11 {
+            part_1: option_from_env("DAY11_PART_1_ROUTE").unwrap_or_else(day11::Route::part_1),
+            part_2: option_from_env("DAY11_PART_2_ROUTE").unwrap_or_else(day11::Route::part_2),
+            allow_unreachable_cycles: option_from_env("DAY11_ALLOW_UNREACHABLE_CYCLES").unwrap_or(false)
+        }),
         Box::new(day12::Day12 {}),
     ];
 
@@ -0,0 +1,92 @@
+use advent_of_code_rust_runner::{Context, Result};
+use num::Integer;
+
+/// A matrix of integers that can be row reduced without leaving the integers.
+///
+/// All arithmetic is checked, so an overflow is reported as an error rather than
+/// producing a wrong answer.
+pub struct Matrix {
+    rows: Vec<Vec<i64>>,
+    num_cols: usize
+}
+
+impl Matrix {
+    pub fn new(rows: Vec<Vec<i64>>) -> Result<Self> {
+        let num_cols = rows.first().map_or(0, |row| row.len());
+        anyhow::ensure!(rows.iter().all(|row| row.len() == num_cols), "Matrix rows must all be the same length");
+        Ok(Self { rows, num_cols })
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn row(&self, index: usize) -> &[i64] {
+        &self.rows[index]
+    }
+
+    /// Reduces the matrix to an integer form of reduced row echelon form, choosing
+    /// pivots only from the first `pivot_cols` columns (so that an augmented column
+    /// is left alone). Each pivot is positive and the only non-zero entry in its
+    /// column, and each row is divided through by the GCD of its entries, but the
+    /// pivots aren't scaled to 1 as that would need fractions.
+    ///
+    /// Returns the pivot column of each pivot row. The pivot rows come first, and
+    /// any rows after them are zero in the first `pivot_cols` columns.
+    pub fn row_reduce(&mut self, pivot_cols: usize) -> Result<Vec<usize>> {
+        let mut pivots: Vec<usize> = Vec::with_capacity(self.rows.len().min(pivot_cols));
+        for col in 0..pivot_cols.min(self.num_cols) {
+            let pivot_row = pivots.len();
+            let Some(source_row) = (pivot_row..self.rows.len()).find(|&row| self.rows[row][col] != 0) else {
+                continue;
+            };
+            self.rows.swap(source_row, pivot_row);
+            self.normalise_row(pivot_row)?;
+
+            // Eliminate the pivot column from every other row, above and below.
+            for row in 0..self.rows.len() {
+                if row != pivot_row && self.rows[row][col] != 0 {
+                    self.eliminate(row, pivot_row, col)?;
+                    self.normalise_row(row)?;
+                }
+            }
+            pivots.push(col);
+        }
+        Ok(pivots)
+    }
+
+    /// Replaces `row` with a multiple of itself minus a multiple of `pivot_row`,
+    /// such that its entry in `col` becomes zero.
+    fn eliminate(&mut self, row: usize, pivot_row: usize, col: usize) -> Result<()> {
+        let pivot = self.rows[pivot_row][col];
+        let target = self.rows[row][col];
+        let gcd = pivot.gcd(&target);
+        let row_multiplier = pivot / gcd;
+        let pivot_multiplier = target / gcd;
+        for k in 0..self.num_cols {
+            self.rows[row][k] = row_multiplier
+                .checked_mul(self.rows[row][k])
+                .zip(pivot_multiplier.checked_mul(self.rows[pivot_row][k]))
+                .and_then(|(a, b)| a.checked_sub(b))
+                .context("Integer overflow during row reduction")?;
+        }
+        Ok(())
+    }
+
+    /// Divides a row through by the GCD of its entries, and negates it if necessary
+    /// to make its first non-zero entry positive.
+    fn normalise_row(&mut self, row: usize) -> Result<()> {
+        let Some(&leading) = self.rows[row].iter().find(|&&val| val != 0) else {
+            return Ok(());
+        };
+        anyhow::ensure!(
+            self.rows[row].iter().all(|&val| val != i64::MIN),
+            "Integer overflow during row reduction");
+        let gcd = self.rows[row].iter().fold(0, |acc, val| acc.gcd(val));
+        let divisor = if leading < 0 { -gcd } else { gcd };
+        for val in self.rows[row].iter_mut() {
+            *val /= divisor;
+        }
+        Ok(())
+    }
+}